@@ -9,15 +9,29 @@ function fetchArticles(categories = 'all') {
             const container = document.querySelector('[role="list"].blog-grid');
             container.innerHTML = ''; // Clear existing items
 
+            const sizes = "(max-width: 479px) 92vw, (max-width: 767px) 94vw, (max-width: 991px) 46vw, (max-width: 1439px) 29vw, 380px";
+
             articles.forEach(article => {
+                // Resized AVIF/WebP variants when the picture is hosted here, the original picture otherwise
+                const sources = (article.picture_sources || [])
+                    .map(source => `<source type="${source.type}" srcset="${source.srcset}" sizes="${sizes}">`)
+                    .join('');
+                const dimensions = article.picture_width && article.picture_height
+                    ? `width="${article.picture_width}" height="${article.picture_height}"`
+                    : '';
                 const articleHTML = `
                     <div role="listitem" class="blur-sibling-item w-dyn-item">
                         <a href="${article.link}" class="text-decoration-none w-inline-block">
                             <div class="image-wrapper border-radius-16px mg-bottom-24px">
-                                <img src="${article.picture_url}" 
-                                     alt="${article.title}"
-                                     sizes="(max-width: 479px) 92vw, (max-width: 767px) 94vw, (max-width: 991px) 46vw, (max-width: 1439px) 29vw, 380px"
-                                     class="image">
+                                <picture>
+                                    ${sources}
+                                    <img src="${article.picture_url}" 
+                                         alt="${article.title}"
+                                         sizes="${sizes}"
+                                         ${dimensions}
+                                         loading="lazy"
+                                         class="image">
+                                </picture>
                             </div>
                             <div class="flex-horizontal---justify-start mg-bottom-16px wrap-8px">
                                 <div class="text-200 text-uppercase color-neutral-600">${article.category}</div>
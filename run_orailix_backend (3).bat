@@ -11,5 +11,5 @@ if %errorlevel% neq 0 (
     pause
     exit /b 1
 )
-target\debug\orailix_backend.exe --ip 127.0.0.1:8080 --origin o
+target\debug\orailix_backend.exe --ip 127.0.0.1:8080
 pause
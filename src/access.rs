@@ -0,0 +1,126 @@
+use std::path::Path;
+use std::pin::Pin;
+use futures_util::{future, FutureExt};
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::StatusCode;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{FromState, State};
+use mime::APPLICATION_JSON;
+use serde::Serialize;
+use crate::config::Config;
+use crate::session_management::current_login;
+use crate::users::Role;
+
+// Route guard letting through only the sessions logged in with a role granting the required one,
+// and confirmed by a second factor when the role needs it (auth.require_second_factor).
+// Added to the pipeline chain of a scope in build_router
+#[derive(Clone)]
+pub struct RequireRole {
+    role: Role,
+}
+
+impl RequireRole {
+    pub fn new(role: Role) -> Self {
+        Self { role }
+    }
+}
+
+impl NewMiddleware for RequireRole {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[derive(Serialize)]
+struct AccessDenied {
+    success: bool,
+    error: String,
+}
+
+// Why the request is refused a role, None when it is granted
+fn denial(state: &State, required: Role) -> Option<(StatusCode, String)> {
+    let auth = &Config::borrow_from(state).auth;
+    let needs_second_factor = auth.require_second_factor && required.grants(auth.second_factor_role);
+    match current_login(state) {
+        None => Some((StatusCode::UNAUTHORIZED, "not logged in".to_string())),
+        Some(login_data) if !login_data.roles.iter().any(|role| role.grants(required)) => {
+            Some((StatusCode::FORBIDDEN, format!("{required} role required")))
+        }
+        Some(login_data) if needs_second_factor && !login_data.two_factor => {
+            Some((StatusCode::FORBIDDEN, "second factor required, log in again with a TOTP code".to_string()))
+        }
+        Some(_) => None,
+    }
+}
+
+// Whether the request would get through RequireRole::new(role), for the handlers serving files of several scopes
+pub(crate) fn is_granted(state: &State, role: Role) -> bool {
+    denial(state, role).is_none()
+}
+
+// Folder of the website reserved to members, see the members scope in build_router
+pub(crate) const MEMBERS_FOLDER: &str = "_pages/hidden";
+
+// Whether a file of the website, by its path within the website folder, is reserved to members
+pub(crate) fn is_members_only(site_path: &Path) -> bool {
+    site_path.starts_with(MEMBERS_FOLDER)
+}
+
+// Path within the website folder of the file a request path points to, a trailing / for a folder.
+// The ., .. and empty segments are refused: the file system would resolve them to a file the router
+// sees under another path, eg /./_pages/hidden/ outside of the members scope
+pub(crate) fn site_path(uri_path: &str) -> Option<&str> {
+    let path = uri_path.strip_prefix('/')?;
+    let segments = path.strip_suffix('/').unwrap_or(path);
+    match segments.is_empty() || segments.split('/').all(|segment| !matches!(segment, "" | "." | "..")) {
+        true => Some(path),
+        false => None,
+    }
+}
+
+impl Middleware for RequireRole {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        match denial(&state, self.role) {
+            None => chain(state),
+            Some((status, error)) => {
+                let response_payload = serde_json::to_string(&AccessDenied { success: false, error }).unwrap_or_default();
+                let res = create_response(&state, status, APPLICATION_JSON, response_payload);
+                future::ok((state, res)).boxed()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_paths_reaching_a_file_another_way() {
+        assert_eq!(site_path("/_pages/hidden/talk/image.webp"), Some("_pages/hidden/talk/image.webp"));
+        assert_eq!(site_path("/news/talks/"), Some("news/talks/"));
+        assert_eq!(site_path("/"), Some(""));
+        assert_eq!(site_path("/./_pages/hidden/talk/image.webp"), None);
+        assert_eq!(site_path("/_pages/./hidden/talk/image.webp"), None);
+        assert_eq!(site_path("//_pages/hidden/talk/image.webp"), None);
+        assert_eq!(site_path("/_pages//hidden/talk/"), None);
+        assert_eq!(site_path("/news/../_pages/hidden/talk/image.webp"), None);
+        assert_eq!(site_path("/news/talks//"), None);
+    }
+
+    #[test]
+    fn reserves_the_members_folder() {
+        assert!(is_members_only(Path::new("_pages/hidden/talk/image.webp")));
+        assert!(is_members_only(Path::new("_pages/./hidden/talk/image.webp")));
+        assert!(is_members_only(Path::new("_pages//hidden/talk/image.webp")));
+        assert!(!is_members_only(Path::new("_pages/hiddenness/image.webp")));
+        assert!(!is_members_only(Path::new("news/talks/image.webp")));
+    }
+}
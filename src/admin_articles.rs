@@ -0,0 +1,583 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use chrono::NaiveDate;
+use data_encoding::HEXLOWER;
+use futures_util::FutureExt;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::{body, Body, Method, StatusCode, Uri};
+use gotham::state::{FromState, State};
+use mime::APPLICATION_JSON;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use crate::articles::{parse_manifest, Manifest};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::config::Config;
+use crate::content_git::{self, Author, Revision};
+use crate::images;
+use crate::markdown::split_front_matter;
+use crate::scaffold::slugify;
+use crate::session_management::current_login;
+use crate::validate::{validate_article, Report, NEWS_PATH};
+use tracing::{error, info};
+
+const ADMIN_ARTICLES_ROUTE: &str = "/api/admin/articles/";
+
+// Two edits of the news section never interleave
+pub(crate) static ARTICLE_WRITES: Mutex<()> = Mutex::new(());
+
+// Article sent to POST and PUT, dates are given as YYYY-MM-DD like with new-article.
+// PUT replaces every field, a missing content keeps the current Markdown body
+#[derive(Deserialize)]
+struct ArticleRequest {
+    title: String,
+    date: String,
+    // Name of the folder after the date, only read on creation (defaults to the title)
+    #[serde(default)]
+    slug: Option<String>,
+    #[serde(default)]
+    summary: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    // File of the article folder or URL
+    #[serde(default)]
+    picture: String,
+    // External page (eg a paper), the article then has a manifest.txt and no page of its own
+    #[serde(default)]
+    url: Option<String>,
+    // Markdown body of the article
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    draft: bool,
+}
+
+#[derive(Default, Serialize)]
+struct ArticleResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    folder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+    // Commit of the edit on the content branch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // Problems found by the content validator
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+// Why a request failed, with the report of the content validator when the article is invalid
+struct Failure {
+    status: StatusCode,
+    error: String,
+    report: Option<Report>,
+}
+
+fn failure(status: StatusCode, error: String) -> Failure {
+    Failure { status, error, report: None }
+}
+
+impl From<Failure> for ArticleResponse {
+    fn from(failure: Failure) -> Self {
+        let report = failure.report.unwrap_or_default();
+        ArticleResponse { error: Some(failure.error), errors: report.errors, warnings: report.warnings, ..ArticleResponse::default() }
+    }
+}
+
+fn success(category: &str, folder: &str, manifest: &Manifest, warnings: Vec<String>) -> ArticleResponse {
+    let link = match manifest.page.starts_with("http") {
+        true => manifest.page.clone(),
+        false => format!("/news/{category}/{folder}/"),
+    };
+    ArticleResponse {
+        success: true,
+        category: Some(category.to_string()),
+        folder: Some(folder.to_string()),
+        link: Some(link),
+        warnings,
+        ..ArticleResponse::default()
+    }
+}
+
+// Category and article folder of /api/admin/articles/<category>[/<folder>[/<action>]], refusing
+// anything that could point outside of the news section
+pub(crate) fn article_location(uri_path: &str) -> Option<(String, Option<String>, Option<String>)> {
+    let mut parts = uri_path.strip_prefix(ADMIN_ARTICLES_ROUTE)?.trim_end_matches('/').split('/');
+    let is_valid = |name: &str| !name.is_empty() && !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    let category = parts.next().filter(|category| is_valid(category))?.to_string();
+    let folder = match parts.next() {
+        Some(folder) if is_valid(folder) => Some(folder.to_string()),
+        Some(_) => return None,
+        None => None,
+    };
+    let action = parts.next().map(str::to_string);
+    match parts.next() {
+        Some(_) => None,
+        None => Some((category, folder, action)),
+    }
+}
+
+fn category_path(category: &str) -> Result<PathBuf, Failure> {
+    let category_path = Path::new(NEWS_PATH).join(category);
+    match category_path.is_dir() {
+        true => Ok(category_path),
+        false => Err(failure(StatusCode::NOT_FOUND, format!("unknown category '{category}'"))),
+    }
+}
+
+fn existing_article(category: &str, folder: &str) -> Result<PathBuf, Failure> {
+    let article_path = category_path(category)?.join(folder);
+    match article_path.is_dir() {
+        true => Ok(article_path),
+        false => Err(failure(StatusCode::NOT_FOUND, format!("unknown article '{category}/{folder}'"))),
+    }
+}
+
+// Manifest of the request, with the picture path in the form expected by the kind of article
+fn request_manifest(request: &ArticleRequest, category: &str, folder: &str, page: &str) -> Result<Manifest, Failure> {
+    let date = NaiveDate::parse_from_str(&request.date, "%Y-%m-%d")
+        .map_err(|e| failure(StatusCode::UNPROCESSABLE_ENTITY, format!("invalid date '{}', expected YYYY-MM-DD: {e}", request.date)))?;
+    // One entry per line, a line break would let a field set another one
+    let mut fields = [request.title.as_str(), &request.summary, &request.picture, page].into_iter().chain(request.authors.iter().map(String::as_str));
+    if fields.any(|field| field.contains(['\n', '\r'])) {
+        return Err(failure(StatusCode::UNPROCESSABLE_ENTITY, "fields must fit on a single line".to_string()))
+    }
+    if request.picture.contains("..") || request.picture.starts_with('/') {
+        return Err(failure(StatusCode::UNPROCESSABLE_ENTITY, format!("invalid picture '{}'", request.picture)))
+    }
+
+    let mut manifest = Manifest::default();
+    manifest.set("title", &request.title);
+    manifest.set("date", &date.format("%Y-%d-%m").to_string());
+    manifest.set("summary", &request.summary);
+    manifest.set("authors", &request.authors.join(","));
+    manifest.set("page", page);
+    manifest.draft = request.draft;
+    // Manifests with a page give paths relative to the news section, the others relative to the article folder
+    let picture = match page.is_empty() || request.picture.is_empty() || request.picture.starts_with("http") {
+        true => request.picture.clone(),
+        false => format!("{category}/{folder}/{}", request.picture),
+    };
+    manifest.set("picture", &picture);
+    Ok(manifest)
+}
+
+// Write the metadata where the article keeps it: the manifest.txt, or else the front matter of index.md
+fn write_manifest(article_path: &Path, manifest: &Manifest, content: Option<&str>) -> Result<(), String> {
+    let manifest_path = article_path.join("manifest.txt");
+    if manifest_path.exists() {
+        if content.is_some() {
+            return Err("the content of an article with a manifest.txt cannot be edited, only its metadata".to_string())
+        }
+        return write_file(&manifest_path, &manifest.to_manifest_txt())
+    }
+    let markdown_path = article_path.join("index.md");
+    let body = match content {
+        Some(content) => content.to_string(),
+        None => fs::read_to_string(&markdown_path).ok()
+            .and_then(|markdown| split_front_matter(&markdown).map(|(_, body)| body.to_string()))
+            .unwrap_or_default(),
+    };
+    write_file(&markdown_path, &format!("{}\n{}", manifest.to_front_matter(), body.trim_start_matches(['\r', '\n'])))
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), String> {
+    fs::write(path, content).map_err(|e| format!("cannot write {}: {e}", path.display()))
+}
+
+// Hidden folder next to the articles, on the same file system so that renaming from it is atomic
+fn scratch_folder(category_path: &Path) -> Result<PathBuf, String> {
+    let mut suffix = [0u8; 16];
+    OsRng.fill_bytes(&mut suffix);
+    let scratch = category_path.join(format!(".admin-{}", HEXLOWER.encode(&suffix)));
+    fs::create_dir(&scratch).map_err(|e| format!("cannot create {}: {e}", scratch.display()))?;
+    Ok(scratch)
+}
+
+fn copy_folder(source: &Path, destination: &Path) -> std::io::Result<()> {
+    fs::create_dir(destination)?;
+    for entry in fs::read_dir(source)?.flatten() {
+        let path = entry.path();
+        match path.is_dir() {
+            true => copy_folder(&path, &destination.join(entry.file_name()))?,
+            false => fs::copy(&path, destination.join(entry.file_name())).map(|_| ())?,
+        }
+    }
+    Ok(())
+}
+
+// Apply a change to a copy of the article folder, check the copy with the content validator and
+// only then put it in place, so that visitors never see a partial or invalid article.
+// Returns the warnings of the validator
+fn replace_article(category_path: &Path, folder: &str, change: impl FnOnce(&Path) -> Result<(), String>) -> Result<Vec<String>, Failure> {
+    let internal_error = |e: String| failure(StatusCode::INTERNAL_SERVER_ERROR, e);
+    let article_path = category_path.join(folder);
+    let scratch = scratch_folder(category_path).map_err(internal_error)?;
+    let staged = scratch.join(folder);
+
+    let result = (|| {
+        match article_path.exists() {
+            true => copy_folder(&article_path, &staged).map_err(|e| internal_error(format!("cannot copy {}: {e}", article_path.display())))?,
+            false => fs::create_dir(&staged).map_err(|e| internal_error(format!("cannot create {}: {e}", staged.display())))?,
+        }
+        change(&staged).map_err(|e| failure(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+
+        // The report names the files where they will be, not in the scratch folder
+        let mut report = validate_article(&staged);
+        let (staged_name, article_name) = (staged.display().to_string(), article_path.display().to_string());
+        for problem in report.errors.iter_mut().chain(report.warnings.iter_mut()) {
+            *problem = problem.replace(&staged_name, &article_name);
+        }
+        if !report.is_valid() {
+            return Err(Failure { status: StatusCode::UNPROCESSABLE_ENTITY, error: "invalid article".to_string(), report: Some(report) })
+        }
+
+        let previous = scratch.join("previous");
+        let replacing = article_path.exists();
+        if replacing {
+            fs::rename(&article_path, &previous).map_err(|e| internal_error(format!("cannot replace {}: {e}", article_path.display())))?;
+        }
+        if let Err(e) = fs::rename(&staged, &article_path) {
+            // Put the previous version back, the scratch folder holding it is removed below
+            if replacing {
+                if let Err(e) = fs::rename(&previous, &article_path) {
+                    error!("cannot restore {}: {e}", article_path.display());
+                }
+            }
+            return Err(internal_error(format!("cannot write {}: {e}", article_path.display())))
+        }
+        Ok(report.warnings)
+    })();
+
+    if let Err(e) = fs::remove_dir_all(&scratch) {
+        error!("cannot remove {}: {e}", scratch.display());
+    }
+    result
+}
+
+fn create_article(category: &str, request: ArticleRequest) -> Result<(StatusCode, ArticleResponse), Failure> {
+    let category_path = category_path(category)?;
+    let slug = slugify(request.slug.as_deref().unwrap_or(&request.title));
+    let date = NaiveDate::parse_from_str(&request.date, "%Y-%m-%d").map(|date| date.format("%Y-%d-%m").to_string()).unwrap_or_default();
+    let folder = match slug.is_empty() {
+        true => date,
+        false => format!("{date}-{slug}"),
+    };
+    let page = request.url.clone().unwrap_or_default();
+    let manifest = request_manifest(&request, category, &folder, &page)?;
+    if category_path.join(&folder).exists() {
+        return Err(failure(StatusCode::CONFLICT, format!("article '{category}/{folder}' already exists")))
+    }
+
+    let warnings = replace_article(&category_path, &folder, |staged| match request.url {
+        Some(_) => write_file(&staged.join("manifest.txt"), &manifest.to_manifest_txt()),
+        None => write_manifest(staged, &manifest, Some(request.content.as_deref().unwrap_or_default())),
+    })?;
+    Ok((StatusCode::CREATED, success(category, &folder, &manifest, warnings)))
+}
+
+fn update_article(category: &str, folder: &str, request: ArticleRequest) -> Result<(StatusCode, ArticleResponse), Failure> {
+    let article_path = existing_article(category, folder)?;
+    // Local HTML pages keep theirs, external articles may change their URL
+    let current_page = parse_manifest(&article_path).map(|manifest| manifest.page).unwrap_or_default();
+    let page = match (&request.url, current_page.starts_with("http")) {
+        (Some(url), _) => url.clone(),
+        (None, true) => return Err(failure(StatusCode::UNPROCESSABLE_ENTITY, "the url of an external article is required".to_string())),
+        (None, false) => current_page,
+    };
+    let manifest = request_manifest(&request, category, folder, &page)?;
+    let warnings = replace_article(&category_path(category)?, folder, |staged| write_manifest(staged, &manifest, request.content.as_deref()))?;
+    Ok((StatusCode::OK, success(category, folder, &manifest, warnings)))
+}
+
+// Hide or show an article without touching anything else
+fn set_draft(category: &str, folder: &str, draft: bool) -> Result<(StatusCode, ArticleResponse), Failure> {
+    let article_path = existing_article(category, folder)?;
+    let mut manifest = parse_manifest(&article_path).ok_or(failure(StatusCode::UNPROCESSABLE_ENTITY, "the article has no valid manifest".to_string()))?;
+    manifest.draft = draft;
+    let warnings = replace_article(&category_path(category)?, folder, |staged| write_manifest(staged, &manifest, None))?;
+    Ok((StatusCode::OK, success(category, folder, &manifest, warnings)))
+}
+
+fn delete_article(category: &str, folder: &str) -> Result<(StatusCode, ArticleResponse), Failure> {
+    let article_path = existing_article(category, folder)?;
+    let internal_error = |e: String| failure(StatusCode::INTERNAL_SERVER_ERROR, e);
+    // Moved aside first, so that the article disappears at once rather than file by file
+    let scratch = scratch_folder(&category_path(category)?).map_err(internal_error)?;
+    let result = fs::rename(&article_path, scratch.join(folder)).map_err(|e| internal_error(format!("cannot delete {}: {e}", article_path.display())));
+    if let Err(e) = fs::remove_dir_all(&scratch) {
+        error!("cannot remove {}: {e}", scratch.display());
+    }
+    result?;
+    let response = ArticleResponse { success: true, category: Some(category.to_string()), folder: Some(folder.to_string()), ..ArticleResponse::default() };
+    Ok((StatusCode::OK, response))
+}
+
+#[derive(Clone, Copy)]
+enum ArticleAction {
+    Create,
+    Update,
+    Publish,
+    Unpublish,
+    Delete,
+}
+
+impl ArticleAction {
+    fn event(self) -> &'static str {
+        match self {
+            ArticleAction::Create => "article_create",
+            ArticleAction::Update => "article_update",
+            ArticleAction::Publish => "article_publish",
+            ArticleAction::Unpublish => "article_unpublish",
+            ArticleAction::Delete => "article_delete",
+        }
+    }
+
+    fn verb(self) -> &'static str {
+        match self {
+            ArticleAction::Create => "Create",
+            ArticleAction::Update => "Update",
+            ArticleAction::Publish => "Publish",
+            ArticleAction::Unpublish => "Unpublish",
+            ArticleAction::Delete => "Delete",
+        }
+    }
+}
+
+// Create, edit, unpublish and delete the articles of orailix.com/news, each change committed on the content branch:
+//  POST   /api/admin/articles/<category>                      create, from an ArticleRequest
+//  PUT    /api/admin/articles/<category>/<folder>             replace the metadata and content
+//  POST   /api/admin/articles/<category>/<folder>/unpublish   hide it, /publish shows it again
+//  DELETE /api/admin/articles/<category>/<folder>             delete the folder
+fn handle_article_request(mut state: State, action: ArticleAction) -> Pin<Box<HandlerFuture>> {
+    let f = async move {
+        let body = match body::to_bytes(Body::take_from(&mut state)).await {
+            Ok(body) => body,
+            Err(e) => return Err((state, e.into())),
+        };
+        let uri_path = Uri::borrow_from(&state).path().to_string();
+        let editor = current_login(&state).map(|login_data| login_data.user_id.clone()).unwrap_or_default();
+        let config = Config::borrow_from(&state);
+        let (cache_dir, git_config, users_file) = (config.images.cache_dir.clone(), config.git.clone(), config.auth.users_file.clone());
+
+        // Copies, renames and git commands block, and so does the lock serializing them
+        let (request_path, author_id) = (uri_path.clone(), editor.clone());
+        let result = tokio::task::spawn_blocking(move || {
+            let (category, folder, sub_action) = article_location(&request_path).ok_or(failure(StatusCode::NOT_FOUND, format!("{request_path} is not an article")))?;
+            let parse_request = || serde_json::from_slice::<ArticleRequest>(&body)
+                .map_err(|e| failure(StatusCode::BAD_REQUEST, format!("invalid article: {e}")));
+            let _guard = ARTICLE_WRITES.lock().map_err(|e| failure(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            let (status, mut response) = match (action, folder.as_deref(), sub_action.as_deref()) {
+                (ArticleAction::Create, None, None) => create_article(&category, parse_request()?)?,
+                (ArticleAction::Update, Some(folder), None) => update_article(&category, folder, parse_request()?)?,
+                (ArticleAction::Publish, Some(folder), Some("publish")) => set_draft(&category, folder, false)?,
+                (ArticleAction::Unpublish, Some(folder), Some("unpublish")) => set_draft(&category, folder, true)?,
+                (ArticleAction::Delete, Some(folder), None) => delete_article(&category, folder)?,
+                _ => return Err(failure(StatusCode::NOT_FOUND, format!("{request_path} is not an article"))),
+            };
+            // Resized pictures of a replaced or deleted article must not outlive it
+            if let Some(folder) = &response.folder {
+                images::invalidate_variants(&cache_dir, &format!("news/{category}/{folder}"));
+                if git_config.enabled {
+                    let author = Author::of(&users_file, &author_id, &git_config);
+                    let message = format!("{} article {category}/{folder}", action.verb());
+                    // The edit is done, a repository problem is reported to the admins rather than to the editor
+                    match content_git::commit(&git_config, &[Path::new(NEWS_PATH).join(&category).join(folder)], &author, &message) {
+                        Ok(revision) => response.revision = revision,
+                        Err(e) => error!("cannot commit {category}/{folder}: {e}"),
+                    }
+                }
+            }
+            Ok((status, response))
+        })
+        .await
+        .unwrap_or_else(|e| Err(failure(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())));
+
+        let (status, response) = match result {
+            Ok((status, response)) => {
+                info!("{editor}: {} {uri_path}", Method::borrow_from(&state));
+                if let (Some(category), Some(folder)) = (&response.category, &response.folder) {
+                    let detail = response.revision.as_ref().map(|revision| format!("commit {revision}")).unwrap_or_default();
+                    let entry = AuditEntry::of_request(&state, action.event(), Some(&format!("{category}/{folder}")), detail);
+                    AuditLog::borrow_from(&state).record(entry);
+                }
+                (status, response)
+            }
+            Err(failure) => (failure.status, ArticleResponse::from(failure)),
+        };
+        let response_payload = serde_json::to_string(&response).unwrap_or_default();
+        let res = create_response(&state, status, APPLICATION_JSON, response_payload);
+        Ok((state, res))
+    };
+    f.boxed()
+}
+
+pub fn create_article_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    handle_article_request(state, ArticleAction::Create)
+}
+
+pub fn update_article_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    handle_article_request(state, ArticleAction::Update)
+}
+
+pub fn publish_article_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    handle_article_request(state, ArticleAction::Publish)
+}
+
+pub fn unpublish_article_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    handle_article_request(state, ArticleAction::Unpublish)
+}
+
+pub fn delete_article_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    handle_article_request(state, ArticleAction::Delete)
+}
+
+#[derive(Default, Serialize)]
+struct HistoryResponse {
+    success: bool,
+    revisions: Vec<Revision>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// GET /api/admin/articles/<category>/<folder>/history: the commits of the content branch changing an article,
+// deleted articles included, to review or revert them with git
+pub fn article_history_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let uri_path = Uri::borrow_from(&state).path().to_string();
+    let git_config = Config::borrow_from(&state).git.clone();
+
+    let f = async move {
+        let result = match article_location(&uri_path) {
+            _ if !git_config.enabled => Err((StatusCode::NOT_FOUND, "content commits are disabled".to_string())),
+            Some((category, Some(folder), Some(action))) if action == "history" => {
+                let path = Path::new(NEWS_PATH).join(category).join(folder);
+                tokio::task::spawn_blocking(move || content_git::history(&git_config, &path))
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+            }
+            _ => Err((StatusCode::NOT_FOUND, format!("{uri_path} is not an article"))),
+        };
+        let (status, response) = match result {
+            Ok(revisions) => (StatusCode::OK, HistoryResponse { success: true, revisions, error: None }),
+            Err((status, error)) => (status, HistoryResponse { error: Some(error), ..HistoryResponse::default() }),
+        };
+        let response_payload = serde_json::to_string(&response).unwrap_or_default();
+        let res = create_response(&state, status, APPLICATION_JSON, response_payload);
+        Ok((state, res))
+    };
+    f.boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(title: &str, date: &str, picture: &str) -> ArticleRequest {
+        ArticleRequest {
+            title: title.to_string(),
+            date: date.to_string(),
+            slug: None,
+            summary: String::new(),
+            authors: Vec::new(),
+            picture: picture.to_string(),
+            url: None,
+            content: None,
+            draft: false,
+        }
+    }
+
+    // A category folder of its own, removed by the test
+    fn category_folder(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("orailix-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn write_article(title: &str) -> impl FnOnce(&Path) -> Result<(), String> + '_ {
+        move |staged| {
+            let manifest = request_manifest(&request(title, "2024-08-14", ""), "talks", "2024-14-08-talk", "").map_err(|e| e.error)?;
+            write_manifest(staged, &manifest, Some("Body"))
+        }
+    }
+
+    fn leftovers(category_path: &Path) -> Vec<String> {
+        fs::read_dir(category_path).unwrap().flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with('.'))
+            .collect()
+    }
+
+    #[test]
+    fn refuses_locations_outside_of_the_news() {
+        assert_eq!(article_location("/api/admin/articles/talks"), Some(("talks".to_string(), None, None)));
+        assert_eq!(
+            article_location("/api/admin/articles/talks/2024-14-08-talk/publish"),
+            Some(("talks".to_string(), Some("2024-14-08-talk".to_string()), Some("publish".to_string())))
+        );
+        assert_eq!(article_location("/api/admin/articles/talks/2024-14-08-talk/"), Some(("talks".to_string(), Some("2024-14-08-talk".to_string()), None)));
+        for uri_path in [
+            "/api/admin/articles/",
+            "/api/admin/articles/..",
+            "/api/admin/articles/talks/..",
+            "/api/admin/articles/talks/.admin-0123",
+            "/api/admin/articles/talks/%2e%2e",
+            "/api/admin/articles/talks//publish",
+            "/api/admin/articles/talks/a b",
+            "/api/admin/articles/talks/2024-14-08-talk/publish/more",
+            "/api/admin/users/alice",
+        ] {
+            assert_eq!(article_location(uri_path), None, "{uri_path}");
+        }
+    }
+
+    #[test]
+    fn builds_single_line_manifests_with_the_folder_date_format() {
+        let manifest = request_manifest(&request("Talk", "2024-08-14", "slides.png"), "talks", "2024-14-08-talk", "").map_err(|e| e.error).unwrap();
+        assert_eq!(manifest.date, "2024-14-08");
+        assert_eq!(manifest.picture, "slides.png");
+        let manifest = request_manifest(&request("Talk", "2024-08-14", "slides.png"), "talks", "2024-14-08-talk", "talks/2024-14-08-talk/index.html").map_err(|e| e.error).unwrap();
+        assert_eq!(manifest.picture, "talks/2024-14-08-talk/slides.png");
+
+        assert!(request_manifest(&request("Talk\npage=https://evil.example", "2024-08-14", ""), "talks", "f", "").is_err());
+        assert!(request_manifest(&request("Talk", "2024-08-14", "a.png\rdraft=false"), "talks", "f", "").is_err());
+        assert!(request_manifest(&request("Talk", "2024-08-14", "../../users.json"), "talks", "f", "").is_err());
+        assert!(request_manifest(&request("Talk", "2024-08-14", "/etc/passwd"), "talks", "f", "").is_err());
+        assert!(request_manifest(&request("Talk", "14/08/2024", ""), "talks", "f", "").is_err());
+    }
+
+    #[test]
+    fn replaces_articles_only_once_they_are_valid() {
+        let category_path = category_folder("replace");
+        let article_path = category_path.join("2024-14-08-talk");
+
+        replace_article(&category_path, "2024-14-08-talk", write_article("First")).map_err(|e| e.error).unwrap();
+        assert_eq!(parse_manifest(&article_path).unwrap().title, "First");
+
+        // A failing change or an invalid result leaves the article as it was
+        let failed = replace_article(&category_path, "2024-14-08-talk", |staged| {
+            fs::write(staged.join("index.md"), "half written").unwrap();
+            Err("cannot write".to_string())
+        });
+        assert_eq!(failed.err().unwrap().status, StatusCode::UNPROCESSABLE_ENTITY);
+        let invalid = replace_article(&category_path, "2024-14-08-talk", |staged| fs::remove_file(staged.join("index.md")).map_err(|e| e.to_string()));
+        assert!(invalid.err().unwrap().report.is_some());
+        assert_eq!(parse_manifest(&article_path).unwrap().title, "First");
+
+        replace_article(&category_path, "2024-14-08-talk", write_article("Second")).map_err(|e| e.error).unwrap();
+        assert_eq!(parse_manifest(&article_path).unwrap().title, "Second");
+        assert!(leftovers(&category_path).is_empty());
+        fs::remove_dir_all(&category_path).unwrap();
+    }
+}
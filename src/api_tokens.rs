@@ -0,0 +1,377 @@
+use std::fs;
+use std::path::Path;
+use std::pin::Pin;
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use futures_util::FutureExt;
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::AUTHORIZATION;
+use gotham::hyper::{HeaderMap, StatusCode};
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{FromState, State};
+use gotham_derive::StateData;
+use mime::APPLICATION_JSON;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use crate::session_management::LoginData;
+use crate::users::{lock_file, write_private, Role, UserStore};
+use tracing::error;
+
+// Tokens are orx_<id>_<secret>: the id finds the token in the store, the secret proves it
+const TOKEN_PREFIX: &str = "orx_";
+// Seconds between two writes of the last use of a token, so that a busy script does not rewrite the store on every request
+const LAST_USED_PRECISION: i64 = 60;
+
+// Long-lived tokens of the automation clients (eg the CI publishing articles), which cannot use cookie sessions
+#[derive(Default, Deserialize, Serialize)]
+pub struct TokenStore {
+    pub tokens: Vec<ApiToken>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ApiToken {
+    pub id: String,
+    // What the token is for, eg "seminar announcements CI"
+    pub name: String,
+    // The token acts as this user, with at most the roles of its scopes
+    pub username: String,
+    pub scopes: Vec<Role>,
+    // SHA-256 of the secret, the secret itself is only shown once at creation
+    pub secret_hash: String,
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+}
+
+impl TokenStore {
+    // Read the store, a missing file means that there is no token
+    pub fn load(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| format!("invalid token store {path}: {e}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TokenStore::default()),
+            Err(e) => Err(format!("cannot read token store {path}: {e}")),
+        }
+    }
+
+    // Re-read the store and write back the changes of the function, holding the lock of the file
+    // so that the server and the token commands do not overwrite each other's changes
+    fn update<T>(path: &str, change: impl FnOnce(&mut TokenStore) -> Result<T, String>) -> Result<T, String> {
+        let _lock = lock_file(path)?;
+        let mut store = TokenStore::load(path)?;
+        let result = change(&mut store)?;
+        store.save(path)?;
+        Ok(result)
+    }
+
+    // Write to a temporary file first, so that the server never reads a partial store
+    fn save(&self, path: &str) -> Result<(), String> {
+        if let Some(folder) = Path::new(path).parent().filter(|folder| !folder.as_os_str().is_empty()) {
+            fs::create_dir_all(folder).map_err(|e| format!("cannot create {}: {e}", folder.display()))?;
+        }
+        let content = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        write_private(Path::new(path), (content + "\n").as_bytes()).map_err(|e| format!("cannot write {path}: {e}"))
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(secret.as_bytes()))
+}
+
+fn random_hex(length: usize) -> String {
+    let mut bytes = vec![0u8; length];
+    OsRng.fill_bytes(&mut bytes);
+    HEXLOWER.encode(&bytes)
+}
+
+// Create a token acting as the user, returns the token to give to the client.
+// The scopes must be granted by the roles of the user, and default to member
+pub fn create_token(tokens_file: &str, users_file: &str, username: &str, name: &str, scopes: Vec<Role>) -> Result<String, String> {
+    let users = UserStore::load(users_file)?;
+    let user = users.find(username).filter(|user| !user.disabled).ok_or(format!("unknown or disabled user '{username}'"))?;
+    let scopes = match scopes.is_empty() {
+        true => vec![Role::Member],
+        false => scopes,
+    };
+    if let Some(scope) = scopes.iter().find(|scope| !user.roles.iter().any(|role| role.grants(**scope))) {
+        return Err(format!("user '{username}' does not have the {scope} role"))
+    }
+
+    let id = random_hex(8);
+    let secret = random_hex(32);
+    let api_token = ApiToken {
+        id: id.clone(),
+        name: name.to_string(),
+        username: username.to_string(),
+        scopes,
+        secret_hash: hash_secret(&secret),
+        created_at: Utc::now().to_rfc3339(),
+        last_used: None,
+    };
+    TokenStore::update(tokens_file, |store| {
+        store.tokens.push(api_token);
+        Ok(())
+    })?;
+    Ok(format!("{TOKEN_PREFIX}{id}_{secret}"))
+}
+
+pub fn revoke_token(tokens_file: &str, id: &str) -> Result<(), String> {
+    TokenStore::update(tokens_file, |store| {
+        let count = store.tokens.len();
+        store.tokens.retain(|token| token.id != id);
+        match store.tokens.len() < count {
+            true => Ok(()),
+            false => Err(format!("unknown token '{id}'")),
+        }
+    })
+}
+
+// One line per token: id, user, scopes, name and last use
+pub fn list_tokens(tokens_file: &str) -> Result<Vec<String>, String> {
+    let store = TokenStore::load(tokens_file)?;
+    Ok(store.tokens.iter()
+        .map(|token| {
+            let scopes = token.scopes.iter().map(Role::to_string).collect::<Vec<String>>().join(",");
+            let last_used = token.last_used.as_deref().unwrap_or("never used");
+            format!("{:<16} {:<20} {:<20} {:<30} {last_used}", token.id, token.username, scopes, token.name)
+        })
+        .collect())
+}
+
+// Login of a request authenticated by a token, read by current_login instead of the session
+#[derive(StateData)]
+pub struct TokenLogin(pub LoginData);
+
+// The identity of a valid token: its user, with the scopes their current roles still grant
+fn authenticate(tokens_file: &str, users_file: &str, token: &str) -> Result<Option<LoginData>, String> {
+    let (id, secret) = match token.strip_prefix(TOKEN_PREFIX).and_then(|token| token.split_once('_')) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let store = TokenStore::load(tokens_file)?;
+    // The comparison takes as long whatever the number of matching characters
+    let secret_hash = hash_secret(secret);
+    let api_token = match store.tokens.iter().find(|api_token| api_token.id == id && bool::from(api_token.secret_hash.as_bytes().ct_eq(secret_hash.as_bytes()))) {
+        Some(api_token) => api_token,
+        None => return Ok(None),
+    };
+    let users = UserStore::load(users_file)?;
+    let user = match users.find(&api_token.username).filter(|user| !user.disabled) {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let roles = api_token.scopes.iter().copied().filter(|scope| user.roles.iter().any(|role| role.grants(*scope))).collect();
+
+    let now = Utc::now();
+    let is_stale = api_token.last_used.as_deref()
+        .and_then(|last_used| DateTime::parse_from_rfc3339(last_used).ok())
+        .is_none_or(|last_used| (now - last_used.with_timezone(&Utc)).num_seconds() >= LAST_USED_PRECISION);
+    // Only the last use changes, on the store as it is now: a token revoked meanwhile stays revoked
+    if is_stale {
+        TokenStore::update(tokens_file, |store| {
+            if let Some(api_token) = store.tokens.iter_mut().find(|api_token| api_token.id == id) {
+                api_token.last_used = Some(now.to_rfc3339());
+            }
+            Ok(())
+        })?;
+    }
+    // Tokens are created by an admin on the server, holding one is as strong as a second factor
+    Ok(Some(LoginData::new(&user.username, roles, true)))
+}
+
+// Authenticates the requests carrying an Authorization: Bearer token. Requests without one go on
+// with their session, an invalid token is refused rather than downgraded to an anonymous request
+#[derive(Clone)]
+pub struct ApiTokenMiddleware {
+    tokens_file: String,
+    users_file: String,
+}
+
+impl ApiTokenMiddleware {
+    pub fn new(tokens_file: &str, users_file: &str) -> Self {
+        Self { tokens_file: tokens_file.to_string(), users_file: users_file.to_string() }
+    }
+}
+
+impl NewMiddleware for ApiTokenMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[derive(Serialize)]
+struct TokenError {
+    success: bool,
+    error: &'static str,
+}
+
+impl Middleware for ApiTokenMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let token = HeaderMap::borrow_from(&state).get(AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let token = match token {
+            Some(token) => token,
+            None => return chain(state),
+        };
+
+        // The stores are files, read and written away from the threads of the server
+        let Self { tokens_file, users_file } = self;
+        let f = async move {
+            let login = tokio::task::spawn_blocking(move || authenticate(&tokens_file, &users_file, &token))
+                .await
+                .unwrap_or_else(|e| Err(format!("cannot check the API token: {e}")));
+            let (status, error) = match login {
+                Ok(Some(login_data)) => {
+                    state.put(TokenLogin(login_data));
+                    return chain(state).await
+                }
+                Ok(None) => (StatusCode::UNAUTHORIZED, "invalid API token"),
+                Err(e) => {
+                    error!("{e}");
+                    (StatusCode::INTERNAL_SERVER_ERROR, "cannot check the API token")
+                }
+            };
+            let response_payload = serde_json::to_string(&TokenError { success: false, error }).unwrap_or_default();
+            let mut res = create_response(&state, status, APPLICATION_JSON, response_payload);
+            if status == StatusCode::UNAUTHORIZED {
+                res.headers_mut().insert("WWW-Authenticate", "Bearer error=\"invalid_token\"".parse().unwrap());
+            }
+            Ok((state, res))
+        };
+        f.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::User;
+
+    // Token and users files of a test in the temporary folder, removed with their locks at the end
+    struct Stores {
+        tokens_file: String,
+        users_file: String,
+    }
+
+    impl Stores {
+        // alice is an editor, bob a member
+        fn new(name: &str) -> Self {
+            let path = |kind: &str| std::env::temp_dir().join(format!("orailix-{kind}-{}-{name}.json", std::process::id())).to_string_lossy().into_owned();
+            let stores = Self { tokens_file: path("tokens"), users_file: path("token-users") };
+            UserStore::update(&stores.users_file, |store| {
+                for (username, role) in [("alice", Role::Editor), ("bob", Role::Member)] {
+                    store.users.push(User {
+                        username: username.to_string(),
+                        password_hash: String::new(),
+                        display_name: String::new(),
+                        roles: vec![role],
+                        disabled: false,
+                        totp: None,
+                    });
+                }
+                Ok(())
+            }).unwrap();
+            stores
+        }
+
+        fn authenticate(&self, token: &str) -> Option<LoginData> {
+            authenticate(&self.tokens_file, &self.users_file, token).unwrap()
+        }
+    }
+
+    impl Drop for Stores {
+        fn drop(&mut self) {
+            for file in [&self.tokens_file, &self.users_file] {
+                let _ = fs::remove_file(file);
+                let _ = fs::remove_file(format!("{file}.lock"));
+            }
+        }
+    }
+
+    #[test]
+    fn stores_only_the_hash_of_the_secret() {
+        let stores = Stores::new("hash");
+        let token = create_token(&stores.tokens_file, &stores.users_file, "alice", "CI", Vec::new()).unwrap();
+        let (id, secret) = token.strip_prefix(TOKEN_PREFIX).unwrap().split_once('_').unwrap();
+        assert_eq!((id.len(), secret.len()), (16, 64));
+
+        let store = TokenStore::load(&stores.tokens_file).unwrap();
+        assert_eq!(store.tokens.len(), 1);
+        assert_eq!((store.tokens[0].id.as_str(), store.tokens[0].scopes.as_slice()), (id, [Role::Member].as_slice()));
+        assert_eq!(store.tokens[0].secret_hash, HEXLOWER.encode(&Sha256::digest(secret.as_bytes())));
+        assert!(!fs::read_to_string(&stores.tokens_file).unwrap().contains(secret));
+    }
+
+    #[test]
+    fn authenticates_with_the_scopes_of_the_token() {
+        let stores = Stores::new("scopes");
+        assert!(create_token(&stores.tokens_file, &stores.users_file, "bob", "CI", vec![Role::Editor]).is_err());
+        assert!(create_token(&stores.tokens_file, &stores.users_file, "carol", "CI", Vec::new()).is_err());
+        let token = create_token(&stores.tokens_file, &stores.users_file, "alice", "CI", vec![Role::Editor]).unwrap();
+
+        let login_data = stores.authenticate(&token).unwrap();
+        assert_eq!((login_data.user_id.as_str(), login_data.roles.as_slice(), login_data.two_factor), ("alice", [Role::Editor].as_slice(), true));
+        // A wrong secret, a truncated token or another prefix
+        let (prefix, secret) = token.rsplit_once('_').unwrap();
+        let flipped = format!("{prefix}_{}{}", if secret.starts_with('0') { '1' } else { '0' }, &secret[1..]);
+        assert!(stores.authenticate(&flipped).is_none());
+        assert!(stores.authenticate(&token[..token.len() - 1]).is_none());
+        assert!(stores.authenticate(&token.replacen(TOKEN_PREFIX, "xyz_", 1)).is_none());
+
+        // The token never grants more than the current roles of its user
+        UserStore::update(&stores.users_file, |store| {
+            store.users.iter_mut().for_each(|user| user.roles = vec![Role::Member]);
+            Ok(())
+        }).unwrap();
+        assert_eq!(stores.authenticate(&token).unwrap().roles, []);
+        UserStore::update(&stores.users_file, |store| {
+            store.users.iter_mut().for_each(|user| user.disabled = true);
+            Ok(())
+        }).unwrap();
+        assert!(stores.authenticate(&token).is_none());
+    }
+
+    #[test]
+    fn records_the_last_use_once_a_minute() {
+        let stores = Stores::new("last-used");
+        let token = create_token(&stores.tokens_file, &stores.users_file, "bob", "CI", Vec::new()).unwrap();
+        let last_used = || TokenStore::load(&stores.tokens_file).unwrap().tokens[0].last_used.clone();
+        assert_eq!(last_used(), None);
+        stores.authenticate(&token).unwrap();
+        let first_use = last_used().unwrap();
+        stores.authenticate(&token).unwrap();
+        assert_eq!(last_used(), Some(first_use));
+
+        // A use older than the precision is written again
+        let stale = (Utc::now() - chrono::Duration::seconds(LAST_USED_PRECISION)).to_rfc3339();
+        TokenStore::update(&stores.tokens_file, |store| {
+            store.tokens[0].last_used = Some(stale.clone());
+            Ok(())
+        }).unwrap();
+        stores.authenticate(&token).unwrap();
+        assert!(last_used().is_some_and(|last_used| last_used > stale));
+    }
+
+    #[test]
+    fn revoked_tokens_are_refused() {
+        let stores = Stores::new("revoked");
+        let token = create_token(&stores.tokens_file, &stores.users_file, "bob", "CI", Vec::new()).unwrap();
+        let other = create_token(&stores.tokens_file, &stores.users_file, "bob", "scripts", Vec::new()).unwrap();
+        let id = &token[TOKEN_PREFIX.len()..TOKEN_PREFIX.len() + 16];
+        revoke_token(&stores.tokens_file, id).unwrap();
+        assert!(revoke_token(&stores.tokens_file, id).is_err());
+        assert!(stores.authenticate(&token).is_none());
+        assert!(stores.authenticate(&other).is_some());
+    }
+}
@@ -1,27 +1,18 @@
 use chrono::NaiveDate;
 use serde::Serialize;
 use std::{fs, path::Path};
-use clap::{App, Arg};
-use std::fs::File;
-use std::io::{BufReader, Read};
-use gotham::pipeline::set::{new_pipeline_set, finalize_pipeline_set};
-use gotham::pipeline::new_pipeline;
-use gotham::middleware::state::StateMiddleware;
-use gotham::middleware::session::{NewSessionMiddleware};
-use gotham::router::builder::{build_router, DrawRoutes, DefineSingleRoute};
-use crate::session_management::{LoginData, OriginDomain, header_formatting};
-use gotham::rustls;
-use gotham::rustls::NoClientAuth;
-use gotham::rustls::internal::pemfile::{certs, pkcs8_private_keys};
+use crate::markdown;
+use crate::images::{picture_dimensions, picture_sources, PictureSource};
 
 use gotham::state::{FromState, State};
 use std::pin::Pin;
 use gotham::handler::HandlerFuture;
-use gotham::hyper::{body, Body, Uri, StatusCode};
+use gotham::hyper::{Uri, StatusCode};
 use gotham::helpers::http::response::create_response;
-use mime::{TEXT_HTML, IMAGE_JPEG, IMAGE_PNG, IMAGE_SVG, TEXT_CSS, TEXT_JAVASCRIPT, TEXT_XML, TEXT_PLAIN};
-use futures_util::{future, FutureExt};
+use futures_util::FutureExt;
 use std::collections::HashMap;
+use tracing::{debug_span, warn};
+use crate::logging;
 
 
 #[derive(Serialize)]
@@ -32,19 +23,79 @@ struct Article {
     picture_url: String,
     category: String,
     link: String,
+    // Intrinsic size and resized variants, only known for pictures hosted on the website
+    picture_width: Option<u32>,
+    picture_height: Option<u32>,
+    picture_sources: Vec<PictureSource>,
 }
 
 
-// Add at top of file
+// Article metadata, read either from manifest.txt or from the front matter of index.md
 #[derive(Default)]
-struct Manifest {
-    title: String,
-    date: String,
-    picture: String,
-    page: String,
+pub(crate) struct Manifest {
+    pub(crate) title: String,
+    pub(crate) date: String,
+    pub(crate) picture: String,
+    pub(crate) page: String,
+    pub(crate) summary: String,
+    pub(crate) authors: Vec<String>,
+    // Unpublished articles are kept but neither listed nor served
+    pub(crate) draft: bool,
 }
 
-pub fn get_articles_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+impl Manifest {
+    // Assign a `key = value` (manifest.txt) or `key: value` (front matter) entry, unknown keys are ignored
+    pub(crate) fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim().to_string();
+        match key.trim() {
+            "title" => self.title = value,
+            "date" => self.date = value,
+            "picture" => self.picture = value,
+            "page" => self.page = value,
+            "summary" => self.summary = value,
+            "authors" => self.authors = value.split(',')
+                .map(|author| author.trim().to_string())
+                .filter(|author| !author.is_empty())
+                .collect(),
+            "draft" => self.draft = value == "true",
+            _ => {}
+        }
+    }
+
+    fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![("title", self.title.clone()), ("date", self.date.clone())];
+        if !self.picture.is_empty() {
+            entries.push(("picture", self.picture.clone()));
+        }
+        if !self.page.is_empty() {
+            entries.push(("page", self.page.clone()));
+        }
+        if !self.summary.is_empty() {
+            entries.push(("summary", self.summary.clone()));
+        }
+        if !self.authors.is_empty() {
+            entries.push(("authors", self.authors.join(", ")));
+        }
+        if self.draft {
+            entries.push(("draft", "true".to_string()));
+        }
+        entries
+    }
+
+    // Content of a manifest.txt, read back by parse_manifest
+    pub(crate) fn to_manifest_txt(&self) -> String {
+        self.entries().into_iter().map(|(key, value)| format!("{key}={value}\n")).collect()
+    }
+
+    // Front matter block of an index.md, read back by split_front_matter
+    pub(crate) fn to_front_matter(&self) -> String {
+        let entries: String = self.entries().into_iter().map(|(key, value)| format!("{key}: {value}\n")).collect();
+        format!("---\n{entries}---\n")
+    }
+}
+
+pub fn get_articles_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let span = debug_span!("get_articles_handler", query = Uri::borrow_from(&state).query().unwrap_or_default());
     let f = async move {
         // Parse query parameters correctly
         let uri = Uri::borrow_from(&state);
@@ -83,7 +134,7 @@ pub fn get_articles_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                             .into_owned();
 
                         // In the article creation code:
-                        if let Some(manifest) = parse_manifest(&article_path) {
+                        if let Some(manifest) = parse_manifest(&article_path).filter(|manifest| !manifest.draft) {
                             // In the article creation block:
                             let (link, picture_url) = if !manifest.page.is_empty() {
                                 // External page - direct URLs
@@ -126,10 +177,17 @@ pub fn get_articles_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                                 )
                             };
 
-                            let formatted_date = match NaiveDate::parse_from_str(&manifest.date, "%Y-%d-%m") {
-                                Ok(date) => date.format("%b %d, %Y").to_string(), // <-- Created here
+                            let picture_path = picture_site_path(&manifest, &category_name, &article_folder);
+                            let dimensions = picture_path.as_deref().and_then(picture_dimensions);
+                            let picture_sources = match (&picture_path, dimensions) {
+                                (Some(site_path), Some((width, _))) => picture_sources(site_path, width),
+                                _ => Vec::new(),
+                            };
+
+                            let formatted_date = match format_manifest_date(&manifest.date) {
+                                Ok(date) => date, // <-- Created here
                                 Err(e) => {
-                                    println!("Error parsing date {}: {}", manifest.date, e);
+                                    warn!("invalid date {} in a manifest: {e}", manifest.date);
                                     continue; // Skip invalid dates
                                 }
                             };
@@ -142,6 +200,9 @@ pub fn get_articles_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                                 picture_url,
                                 category: category_name.to_string(),
                                 link,
+                                picture_width: dimensions.map(|(width, _)| width),
+                                picture_height: dimensions.map(|(_, height)| height),
+                                picture_sources,
                             });
                         }
                     }
@@ -164,46 +225,71 @@ pub fn get_articles_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
             articles.truncate(l);
         }
 
-        // Manual JSON serialization with proper escaping
-        let mut json = String::from("[");
-        for (i, article) in articles.iter().enumerate() {
-            if i > 0 {
-                json.push(',');
-            }
-            json.push_str(&format!(
-                r#"{{"title":"{}","date":"{}","formatted_date":"{}","picture_url":"{}","category":"{}","link":"{}"}}"#,
-                article.title.replace('"', "\\\""),
-                article.date.replace('"', "\\\""),
-                article.formatted_date.replace('"', "\\\""),
-                article.picture_url.replace('"', "\\\""),
-                article.category.replace('"', "\\\""),
-                article.link.replace('"', "\\\"")
-            ));
-        }
-        json.push(']');
+        let json = serde_json::to_string(&articles).unwrap_or_else(|_| "[]".to_string());
 
-        let mut res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, json);
-        res = header_formatting(res, &state);
+        let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, json);
         Ok((state, res))
     };
-    f.boxed()
+    logging::timed(span, f.boxed())
 }
 
-fn parse_manifest(path: &Path) -> Option<Manifest> {
+// Location of the picture in the website folder, None when hosted elsewhere
+// Manifests with a page give it relative to the news section, the others relative to the article folder
+pub(crate) fn picture_site_path(manifest: &Manifest, category: &str, folder: &str) -> Option<String> {
+    match (manifest.picture.is_empty() || manifest.picture.starts_with("http"), manifest.page.is_empty()) {
+        (true, _) => None,
+        (false, true) => Some(format!("news/{}/{}/{}", category, folder, manifest.picture)),
+        (false, false) => Some(format!("news/{}", manifest.picture.trim_start_matches('/'))),
+    }
+}
+
+// Pictures of every article, in the website folder
+pub(crate) fn article_picture_paths() -> Vec<String> {
+    let mut pictures = Vec::new();
+    for category_entry in fs::read_dir("orailix.com/news").into_iter().flatten().flatten() {
+        let category_name = category_entry.file_name().to_string_lossy().into_owned();
+        for article_entry in fs::read_dir(category_entry.path()).into_iter().flatten().flatten() {
+            let article_folder = article_entry.file_name().to_string_lossy().into_owned();
+            if let Some(picture) = parse_manifest(&article_entry.path()).and_then(|manifest| picture_site_path(&manifest, &category_name, &article_folder)) {
+                pictures.push(picture);
+            }
+        }
+    }
+    pictures
+}
+
+// Whether a file of the website belongs to an unpublished article, by its path within the website folder,
+// eg news/talks/<folder>/index.html. The article is the one of the canonical category/folder: the paths
+// with ., .. or empty segments, which the file system resolves to the same folder, are all refused
+pub(crate) fn is_unpublished(site_path: &str) -> bool {
+    let segments = site_path.split('/').collect::<Vec<&str>>();
+    if segments.iter().any(|segment| matches!(*segment, "" | "." | "..")) {
+        return true
+    }
+    match segments.as_slice() {
+        ["news", category, folder, ..] => parse_manifest(&Path::new("orailix.com/news").join(category).join(folder)).is_some_and(|manifest| manifest.draft),
+        _ => false,
+    }
+}
+
+// Manifest dates are written YYYY-DD-MM, like the article folders
+pub(crate) fn format_manifest_date(date: &str) -> Result<String, chrono::ParseError> {
+    NaiveDate::parse_from_str(date, "%Y-%d-%m").map(|date| date.format("%b %d, %Y").to_string())
+}
+
+pub(crate) fn parse_manifest(path: &Path) -> Option<Manifest> {
     let manifest_path = path.join("manifest.txt");
-    let content = fs::read_to_string(manifest_path).ok()?;
+    let content = match fs::read_to_string(manifest_path) {
+        Ok(content) => content,
+        // Markdown articles carry their metadata in the front matter of index.md
+        Err(_) => return markdown::read_front_matter(path),
+    };
 
     let mut manifest = Manifest::default();
     for line in content.lines() {
         let parts: Vec<&str> = line.splitn(2, '=').collect();
         if parts.len() == 2 {
-            match parts[0].trim() {
-                "title" => manifest.title = parts[1].trim().to_string(),
-                "date" => manifest.date = parts[1].trim().to_string(),
-                "picture" => manifest.picture = parts[1].trim().to_string(),
-                "page" => manifest.page = parts[1].trim().to_string(),
-                _ => {}
-            }
+            manifest.set(parts[0], parts[1]);
         }
     }
 
@@ -212,4 +298,17 @@ fn parse_manifest(path: &Path) -> Option<Manifest> {
     } else {
         None
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_other_paths_to_an_article() {
+        for site_path in ["news//talks/draft/index.html", "news/talks//draft/index.html", "news/./talks/draft/index.html", "news/talks/./draft/index.html", "news/x/../talks/draft/index.html"] {
+            assert!(is_unpublished(site_path), "{site_path}");
+        }
+        assert!(!is_unpublished("news/talks/index.html"));
+        assert!(!is_unpublished("assets/img/bayen.webp"));
+    }
+}
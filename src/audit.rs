@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Days, NaiveDate, TimeDelta, Utc};
+use futures_util::FutureExt;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::{StatusCode, Uri};
+use gotham::state::{client_addr, FromState, State};
+use gotham_derive::StateData;
+use mime::APPLICATION_JSON;
+use serde::{Deserialize, Serialize};
+use crate::session_management::current_login;
+use tracing::error;
+
+// Entries returned when the query does not give a limit, and at most
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 10000;
+
+// Who logged in and what they changed, one JSON object per line appended to the file of [audit] in the configuration.
+// The server never rewrites the file, rotate it with the usual tools
+#[derive(Clone, StateData)]
+pub struct AuditLog {
+    path: Arc<String>,
+    // Lines of concurrent requests must not interleave
+    lock: Arc<Mutex<()>>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub time: String,
+    // eg login, login_failed, sessions_revoked, article_update, upload
+    pub event: String,
+    // Username who did it, or tried to for the failed logins
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    // What it was done to, eg a username or an article
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub detail: String,
+}
+
+impl AuditEntry {
+    pub fn new(event: &str, actor: Option<&str>, ip: Option<&str>, target: Option<&str>, detail: String) -> Self {
+        Self {
+            time: Utc::now().to_rfc3339(),
+            event: event.to_string(),
+            actor: actor.map(str::to_string),
+            ip: ip.map(str::to_string),
+            target: target.map(str::to_string),
+            detail,
+        }
+    }
+
+    // An event of the current request, done by its logged in user
+    pub fn of_request(state: &State, event: &str, target: Option<&str>, detail: String) -> Self {
+        let actor = current_login(state).map(|login_data| login_data.user_id.as_str());
+        let ip = client_addr(state).map(|address| address.ip().to_string());
+        Self::new(event, actor, ip.as_deref(), target, detail)
+    }
+
+    // One line for the terminal: time, event, actor, IP, target and detail
+    pub fn to_line(&self) -> String {
+        let field = |value: &Option<String>| value.clone().unwrap_or("-".to_string());
+        format!("{} {:<18} {:<16} {:<16} {:<40} {}", self.time, self.event, field(&self.actor), field(&self.ip), field(&self.target), self.detail)
+    }
+}
+
+// Filters of audit query and of GET /api/admin/audit, every one optional.
+// since and until are RFC 3339 times or YYYY-MM-DD days, both included
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct AuditQuery {
+    pub event: Option<String>,
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<usize>,
+}
+
+type Bound = Option<DateTime<Utc>>;
+
+impl AuditQuery {
+    // Times of the first and last entries to keep, both included
+    fn period(&self) -> Result<(Bound, Bound), String> {
+        let parse = |bound: &Option<String>, end: bool| -> Result<Bound, String> {
+            let bound = match bound.as_deref().map(str::trim).filter(|bound| !bound.is_empty()) {
+                Some(bound) => bound,
+                None => return Ok(None),
+            };
+            if let Ok(time) = DateTime::parse_from_rfc3339(bound) {
+                return Ok(Some(time.with_timezone(&Utc)))
+            }
+            let day = NaiveDate::parse_from_str(bound, "%Y-%m-%d").map_err(|_| format!("invalid time '{bound}', expected YYYY-MM-DD or an RFC 3339 time"))?;
+            let start = day.and_hms_opt(0, 0, 0).map(|time| time.and_utc());
+            // A day given as the end includes all of it
+            match end {
+                true => Ok(day.checked_add_days(Days::new(1)).and_then(|next| next.and_hms_opt(0, 0, 0)).map(|time| time.and_utc() - TimeDelta::nanoseconds(1))),
+                false => Ok(start),
+            }
+        };
+        Ok((parse(&self.since, false)?, parse(&self.until, true)?))
+    }
+
+    // Events and targets match exactly, or by prefix when they end with *, eg article_* or talks/*
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let matches = |filter: &Option<String>, value: &Option<String>| match (filter.as_deref(), value.as_deref()) {
+            (None, _) => true,
+            (Some(filter), Some(value)) => match filter.strip_suffix('*') {
+                Some(prefix) => value.starts_with(prefix),
+                None => value == filter,
+            },
+            (Some(_), None) => false,
+        };
+        matches(&self.event, &Some(entry.event.clone()))
+            && matches(&self.actor, &entry.actor)
+            && matches(&self.ip, &entry.ip)
+            && matches(&self.target, &entry.target)
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: &str) -> Self {
+        Self { path: Arc::new(path.to_string()), lock: Arc::default() }
+    }
+
+    // Failing to audit must not fail the request, the error is only reported
+    pub fn record(&self, entry: AuditEntry) {
+        if let Err(e) = self.append(&entry) {
+            error!("cannot write the audit log {}: {e}", self.path);
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<(), String> {
+        let line = serde_json::to_string(entry).map_err(|e| e.to_string())? + "\n";
+        let _guard = self.lock.lock().map_err(|e| e.to_string())?;
+        if let Some(folder) = Path::new(self.path.as_str()).parent().filter(|folder| !folder.as_os_str().is_empty()) {
+            fs::create_dir_all(folder).map_err(|e| e.to_string())?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(self.path.as_str()).map_err(|e| e.to_string())?;
+        file.write_all(line.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    // The most recent entries matching the query, oldest first. A missing file is an empty log
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, String> {
+        let (since, until) = query.period()?;
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let file = match File::open(self.path.as_str()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("cannot read the audit log {}: {e}", self.path)),
+        };
+
+        let mut entries = VecDeque::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| format!("cannot read the audit log {}: {e}", self.path))?;
+            // A line cut by a crash is skipped rather than hiding the rest of the log
+            let entry = match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let time = DateTime::parse_from_rfc3339(&entry.time).ok().map(|time| time.with_timezone(&Utc));
+            let in_period = since.is_none_or(|since| time.is_some_and(|time| time >= since))
+                && until.is_none_or(|until| time.is_some_and(|time| time <= until));
+            if in_period && query.matches(&entry) {
+                if entries.len() == limit {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+        }
+        Ok(entries.into())
+    }
+}
+
+#[derive(Default, Serialize)]
+struct AuditResponse {
+    success: bool,
+    entries: Vec<AuditEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// GET /api/admin/audit?event=&actor=&ip=&target=&since=&until=&limit=, the filters of AuditQuery
+pub fn audit_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let query = Uri::borrow_from(&state).query().unwrap_or_default();
+    let query = serde_urlencoded::from_str::<AuditQuery>(query)
+        .map_err(|e| format!("invalid query: {e}"))
+        .and_then(|query| query.period().map(|_| query));
+    let log = AuditLog::borrow_from(&state).clone();
+
+    let f = async move {
+        let result = match query {
+            // The whole file is read and parsed, away from the threads of the server
+            Ok(query) => tokio::task::spawn_blocking(move || log.query(&query))
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()))
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e)),
+            Err(e) => Err((StatusCode::BAD_REQUEST, e)),
+        };
+        let (status, response) = match result {
+            Ok(entries) => (StatusCode::OK, AuditResponse { success: true, entries, error: None }),
+            Err((status, error)) => (status, AuditResponse { error: Some(error), ..AuditResponse::default() }),
+        };
+        let response_payload = serde_json::to_string(&response).unwrap_or_default();
+        let res = create_response(&state, status, APPLICATION_JSON, response_payload);
+        Ok((state, res))
+    };
+    f.boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: &str, event: &str, actor: Option<&str>, target: Option<&str>) -> AuditEntry {
+        AuditEntry { time: time.to_string(), ..AuditEntry::new(event, actor, Some("127.0.0.1"), target, String::new()) }
+    }
+
+    fn temporary_log(name: &str, lines: &[String]) -> AuditLog {
+        let path = std::env::temp_dir().join(format!("orailix-audit-{}-{name}.jsonl", std::process::id()));
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        AuditLog::new(&path.to_string_lossy())
+    }
+
+    #[test]
+    fn filters_by_event_actor_and_target_prefix() {
+        let query = AuditQuery { event: Some("article_*".to_string()), target: Some("talks/*".to_string()), ..AuditQuery::default() };
+        assert!(query.matches(&entry("2024-05-03T10:00:00Z", "article_update", Some("alice"), Some("talks/2024-03-05-a"))));
+        assert!(!query.matches(&entry("2024-05-03T10:00:00Z", "article_update", Some("alice"), Some("tech/2024-03-05-a"))));
+        assert!(!query.matches(&entry("2024-05-03T10:00:00Z", "upload", Some("alice"), Some("talks/2024-03-05-a"))));
+
+        let query = AuditQuery { actor: Some("alice".to_string()), ..AuditQuery::default() };
+        assert!(query.matches(&entry("2024-05-03T10:00:00Z", "login", Some("alice"), None)));
+        assert!(!query.matches(&entry("2024-05-03T10:00:00Z", "login", Some("alicia"), None)));
+        assert!(!query.matches(&entry("2024-05-03T10:00:00Z", "sessions_revoked", None, Some("alice"))));
+    }
+
+    #[test]
+    fn days_include_their_whole_period() {
+        let query = AuditQuery { since: Some("2024-05-03".to_string()), until: Some("2024-05-03".to_string()), ..AuditQuery::default() };
+        let (since, until) = query.period().unwrap();
+        assert_eq!(since.unwrap().to_rfc3339(), "2024-05-03T00:00:00+00:00");
+        assert!(until.unwrap() > DateTime::parse_from_rfc3339("2024-05-03T23:59:59.999Z").unwrap());
+        assert!(until.unwrap() < DateTime::parse_from_rfc3339("2024-05-04T00:00:00Z").unwrap());
+
+        let query = AuditQuery { since: Some("yesterday".to_string()), ..AuditQuery::default() };
+        assert!(query.period().is_err());
+    }
+
+    #[test]
+    fn keeps_the_latest_matching_entries() {
+        let mut lines = (1..=5)
+            .map(|day| serde_json::to_string(&entry(&format!("2024-05-0{day}T10:00:00Z"), "login", Some("alice"), None)).unwrap())
+            .collect::<Vec<String>>();
+        // Another event, and a line cut by a crash
+        lines.push(r#"{"time":"2024-05-06T10:00:00Z","event":"login_lockout","actor":"bob","ip":"127.0.0.1","detail":""}"#.to_string());
+        lines.push(r#"{"time":"2024-05-07T1"#.to_string());
+        let log = temporary_log("latest", &lines);
+
+        let entries = log.query(&AuditQuery { limit: Some(2), ..AuditQuery::default() }).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.time.as_str()).collect::<Vec<&str>>(), ["2024-05-05T10:00:00Z", "2024-05-06T10:00:00Z"]);
+        assert_eq!(entries[1].actor.as_deref(), Some("bob"));
+
+        let query = AuditQuery { event: Some("login".to_string()), since: Some("2024-05-02".to_string()), until: Some("2024-05-03".to_string()), ..AuditQuery::default() };
+        assert_eq!(log.query(&query).unwrap().len(), 2);
+        fs::remove_file(log.path.as_str()).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::BTreeMap;
+use std::fs;
+use serde::Deserialize;
+use gotham_derive::StateData;
+use crate::users::Role;
+
+// Configuration file read at startup, every section and field falls back to its default when missing
+pub const DEFAULT_CONFIG_PATH: &str = "orailix.toml";
+
+#[derive(Clone, Default, Deserialize, StateData)]
+#[serde(default)]
+pub struct Config {
+    pub site: SiteConfig,
+    pub images: ImagesConfig,
+    pub auth: AuthConfig,
+    pub sessions: SessionsConfig,
+    pub csrf: CsrfConfig,
+    pub login: LoginConfig,
+    pub audit: AuditConfig,
+    pub uploads: UploadsConfig,
+    pub git: GitConfig,
+    pub headers: HeadersConfig,
+    pub csp: CspConfig,
+    pub cors: CorsConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SiteConfig {
+    // Scheme and host the website is publicly reached at, used to build absolute URLs (eg link previews)
+    pub public_origin: String,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        Self {
+            public_origin: "https://orailix.com".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ImagesConfig {
+    // Folder where resized pictures are kept, it can be deleted at any time
+    pub cache_dir: String,
+    // Generate the variants of the article pictures at startup rather than on their first request
+    pub pregenerate: bool,
+    // Widths and heights /img accepts, any other size is refused so that the cache cannot be flooded
+    pub allowed_sizes: Vec<u32>,
+    // Seconds browsers and proxies may keep a resized picture before checking it again
+    pub max_age: u32,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            cache_dir: "cache/images".to_string(),
+            pregenerate: true,
+            allowed_sizes: vec![64, 128, 256, 400, 800, 1200, 1600],
+            max_age: 86400,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    // JSON file of the accounts allowed to log in, with their Argon2id password hashes
+    pub users_file: String,
+    // Seconds after the login when a session ends, whatever the activity
+    pub session_lifetime: i64,
+    // Seconds without any request after which a session ends
+    pub idle_timeout: i64,
+    // Routes needing second_factor_role or a higher one refuse the sessions logged in without a second factor
+    pub require_second_factor: bool,
+    pub second_factor_role: Role,
+    // Name shown by the authenticator apps next to the username
+    pub totp_issuer: String,
+    // JSON file of the API tokens, with the SHA-256 of their secret
+    pub tokens_file: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            users_file: "data/users.json".to_string(),
+            session_lifetime: 43200,
+            idle_timeout: 1800,
+            require_second_factor: true,
+            second_factor_role: Role::Editor,
+            totp_issuer: "ORAILIX".to_string(),
+            tokens_file: "data/tokens.json".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionsConfig {
+    // memory: sessions are lost on restart, file: logged in sessions are kept in dir
+    pub backend: String,
+    pub dir: String,
+    // Seconds between two removals of the expired sessions
+    pub gc_interval: u64,
+    // SameSite attribute of the session and CSRF cookies
+    pub same_site: SameSite,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    // Cookies are sent when following a link from another website
+    Lax,
+    // Cookies are only sent by pages of the website, visitors arriving from a link look logged out
+    Strict,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CsrfConfig {
+    // Key signing the CSRF tokens, created on first start
+    pub key_file: String,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        Self {
+            key_file: "data/csrf.key".to_string(),
+        }
+    }
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            backend: "file".to_string(),
+            dir: "data/sessions".to_string(),
+            gc_interval: 600,
+            same_site: SameSite::Lax,
+        }
+    }
+}
+
+// Brute-force protection of the login, failures are counted per IP address and per username
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct LoginConfig {
+    // Failures allowed before the attempts are delayed
+    pub free_attempts: u32,
+    // Seconds to wait after the first delayed failure, doubled on each of the following ones
+    pub base_delay: u64,
+    pub max_delay: u64,
+    // Failures after which the IP address or the username is locked for lockout_duration seconds
+    pub lockout_threshold: u32,
+    pub lockout_duration: u64,
+    // Seconds without failure after which the counters start again from zero
+    pub window: u64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: 5,
+            base_delay: 1,
+            max_delay: 60,
+            lockout_threshold: 20,
+            lockout_duration: 900,
+            window: 900,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    // JSON Lines file the security events are appended to
+    pub file: String,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            file: "data/audit.jsonl".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct UploadsConfig {
+    // Folder of the website receiving the files not attached to an article
+    pub media_folder: String,
+    // Bytes allowed per file and per request
+    pub max_file_size: u64,
+    pub max_request_size: u64,
+    // Extensions of the accepted types, among jpg, png, gif, webp and pdf
+    pub allowed_types: Vec<String>,
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        Self {
+            media_folder: "media".to_string(),
+            max_file_size: 10 * 1024 * 1024,
+            max_request_size: 50 * 1024 * 1024,
+            allowed_types: ["jpg", "png", "gif", "webp", "pdf"].map(str::to_string).to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct GitConfig {
+    // Commit the edits made through the admin API, pushing them stays manual
+    pub enabled: bool,
+    // Repository holding the website folder, and the branch receiving the commits (created from HEAD when missing)
+    pub repository: String,
+    pub branch: String,
+    // Commits are authored by the logged in user as <username>@<email_domain>, and committed by the server
+    pub email_domain: String,
+    pub committer_name: String,
+    pub committer_email: String,
+}
+
+impl Default for GitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            repository: ".".to_string(),
+            branch: "content".to_string(),
+            email_domain: "orailix.com".to_string(),
+            committer_name: "ORAILIX backend".to_string(),
+            committer_email: "backend@orailix.com".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct HeadersConfig {
+    // Strict-Transport-Security, only sent when serving HTTPS
+    pub hsts: HstsConfig,
+    // Values of the security headers added to every response, an empty value leaves the header out
+    pub frame_options: String,
+    pub content_type_options: String,
+    pub permissions_policy: String,
+    // Any other header to add, by name
+    pub extra: BTreeMap<String, String>,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        Self {
+            hsts: HstsConfig::default(),
+            frame_options: "SAMEORIGIN".to_string(),
+            content_type_options: "nosniff".to_string(),
+            permissions_policy: PERMISSIONS_POLICY.to_string(),
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct HstsConfig {
+    pub enabled: bool,
+    // Seconds the browsers keep to HTTPS
+    pub max_age: u64,
+    // Also for every subdomain, which must then all serve HTTPS
+    pub include_subdomains: bool,
+    // Ask for the browsers' preload lists, which requires include_subdomains and a max_age of a year at least
+    pub preload: bool,
+}
+
+impl Default for HstsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_age: 63072000,
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CspConfig {
+    pub enabled: bool,
+    // Send Content-Security-Policy-Report-Only: violations are reported to /api/csp-report but not blocked
+    pub report_only: bool,
+    // {nonce} is replaced by the nonce of the response, which is added to the inline <script> of the HTML pages
+    pub policy: String,
+}
+
+impl Default for CspConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            report_only: true,
+            policy: CSP_POLICY.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    // Origins other websites call the API from: exact (https://orailix.com) or any subdomain (https://*.orailix.com),
+    // * for any origin but only without credentials. The --origin arguments are added to them
+    pub allowed_origins: Vec<String>,
+    // What cross-origin requests may use, answered to the preflight requests
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    // Let cross-origin requests carry the session cookie
+    pub allow_credentials: bool,
+    // Seconds the browsers may keep a preflight answer
+    pub max_age: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["https://orailix.com".to_string(), "https://*.orailix.com".to_string()],
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"].iter().map(|method| method.to_string()).collect(),
+            allowed_headers: ["Content-Type", "X-CSRF-Token", "Authorization"].iter().map(|header| header.to_string()).collect(),
+            allow_credentials: true,
+            max_age: 600,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    // error, warn, info, debug or trace, for the messages of the server. RUST_LOG overrides it, eg RUST_LOG=orailix_backend=debug,gotham=debug
+    pub level: String,
+    // Format of the access log and of the messages
+    pub format: LogFormat,
+    // One line per request
+    pub access_log: bool,
+    // Where the logs are written, the files are kept in directory
+    pub output: LogOutput,
+    pub directory: String,
+    // A new file every minute, hour or day, or a single file
+    pub rotation: LogRotation,
+    // Rotated files kept, the older ones are removed
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Combined,
+            access_log: true,
+            output: LogOutput::Stdout,
+            directory: "logs".to_string(),
+            rotation: LogRotation::Daily,
+            max_files: 14,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // Access log in the Combined Log Format of Apache and nginx, messages as text
+    Combined,
+    // One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    Stdout,
+    File,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+// Scripts of the website, of the CDNs it uses and inline ones with the nonce; styles, pictures and fonts from anywhere in HTTPS
+const CSP_POLICY: &str = "default-src 'self'; script-src 'self' 'nonce-{nonce}' https://cdn.jsdelivr.net https://unpkg.com https://assets-global.website-files.com; style-src 'self' 'unsafe-inline' https:; img-src 'self' data: https:; font-src 'self' data: https:; connect-src 'self'; frame-ancestors 'self'; base-uri 'self'; object-src 'none'; report-uri /api/csp-report";
+
+// Every browser feature the website does not use is disabled
+const PERMISSIONS_POLICY: &str = "accelerometer=(), ambient-light-sensor=(), autoplay=(), battery=(), camera=(), cross-origin-isolated=(), display-capture=(), document-domain=(), encrypted-media=(), execution-while-not-rendered=(), execution-while-out-of-viewport=(), fullscreen=(), geolocation=(), gyroscope=(), keyboard-map=(), magnetometer=(), microphone=(), midi=(), navigation-override=(), payment=(), picture-in-picture=(), publickey-credentials-get=(), screen-wake-lock=(), sync-xhr=(), usb=(), web-share=(), xr-spatial-tracking=()";
+
+impl Config {
+    // Load the configuration, a missing file gives the default configuration but an invalid one is fatal
+    pub fn load(path: &str) -> Self {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                println!("No configuration loaded from {path} ({e}), using defaults");
+                return Config::default()
+            }
+        };
+        match toml::from_str::<Config>(&content) {
+            Ok(mut config) => {
+                config.site.public_origin = config.site.public_origin.trim_end_matches('/').to_string();
+                config
+            }
+            Err(e) => panic!("Invalid configuration file {path}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use crate::config::GitConfig;
+use crate::users::UserStore;
+
+// Revisions returned by the history of an article, most recent first
+const MAX_REVISIONS: usize = 200;
+
+// Who made an edit, as recorded in the commit
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+impl Author {
+    // The display name of the user, or their username when they have none
+    pub fn of(users_file: &str, username: &str, config: &GitConfig) -> Self {
+        let name = UserStore::load(users_file).ok()
+            .and_then(|users| users.find(username).map(|user| user.display_name.clone()))
+            .filter(|display_name| !display_name.is_empty())
+            .unwrap_or(username.to_string());
+        Self { name, email: format!("{username}@{}", config.email_domain) }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Revision {
+    pub commit: String,
+    pub author: String,
+    pub email: String,
+    pub date: String,
+    pub message: String,
+}
+
+// Index used to build a commit without touching the index of the checkout, removed when dropped
+struct TemporaryIndex(PathBuf);
+
+impl TemporaryIndex {
+    fn new() -> Self {
+        static INDEXES: AtomicU64 = AtomicU64::new(0);
+        let name = format!("orailix-index-{}-{}", std::process::id(), INDEXES.fetch_add(1, Ordering::Relaxed));
+        Self(env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TemporaryIndex {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Run git in the repository, returns its trimmed output
+fn git(config: &GitConfig, args: &[&str], index: Option<&TemporaryIndex>, author: Option<&Author>) -> Result<String, String> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(&config.repository).args(args);
+    if let Some(index) = index {
+        command.env("GIT_INDEX_FILE", &index.0);
+    }
+    if let Some(author) = author {
+        command
+            .env("GIT_AUTHOR_NAME", &author.name)
+            .env("GIT_AUTHOR_EMAIL", &author.email)
+            .env("GIT_COMMITTER_NAME", &config.committer_name)
+            .env("GIT_COMMITTER_EMAIL", &config.committer_email);
+    }
+    let output = command.output().map_err(|e| format!("cannot run git: {e}"))?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => Err(format!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())),
+    }
+}
+
+// Paths are given relative to the working directory of the server, which may not be the repository
+fn absolute(path: &Path) -> Result<String, String> {
+    let path = env::current_dir().map_err(|e| format!("cannot read the working directory: {e}"))?.join(path);
+    Ok(path.to_string_lossy().to_string())
+}
+
+// Latest commit of the content branch, or of HEAD until the branch is created
+fn tip(config: &GitConfig) -> Option<String> {
+    branch_tip(config).or_else(|| git(config, &["rev-parse", "--verify", "--quiet", "HEAD^{commit}"], None, None).ok())
+}
+
+// Latest commit of the content branch, None until it is created
+fn branch_tip(config: &GitConfig) -> Option<String> {
+    let branch = format!("refs/heads/{}^{{commit}}", config.branch);
+    git(config, &["rev-parse", "--verify", "--quiet", &branch], None, None).ok()
+}
+
+// Commit the current state of the paths (files or folders, deleted ones included) on the content branch.
+// The commit is built in a temporary index, so the branch does not need to be checked out.
+// Returns the new commit, or None when the paths did not change
+pub fn commit(config: &GitConfig, paths: &[PathBuf], author: &Author, message: &str) -> Result<Option<String>, String> {
+    let branch_tip = branch_tip(config);
+    let parent = branch_tip.clone().or_else(|| tip(config));
+    let index = TemporaryIndex::new();
+    match &parent {
+        Some(parent) => git(config, &["read-tree", parent], Some(&index), None)?,
+        None => git(config, &["read-tree", "--empty"], Some(&index), None)?,
+    };
+    for path in paths {
+        let pathspec = absolute(path)?;
+        match path.exists() {
+            true => git(config, &["add", "--all", "--", &pathspec], Some(&index), None)?,
+            false => git(config, &["rm", "-r", "--cached", "--quiet", "--ignore-unmatch", "--", &pathspec], Some(&index), None)?,
+        };
+    }
+    let tree = git(config, &["write-tree"], Some(&index), None)?;
+    if let Some(parent) = &parent {
+        if git(config, &["rev-parse", &format!("{parent}^{{tree}}")], None, None)? == tree {
+            return Ok(None)
+        }
+    }
+
+    let mut commit_args = vec!["commit-tree", tree.as_str(), "-m", message];
+    if let Some(parent) = &parent {
+        commit_args.extend(["-p", parent.as_str()]);
+    }
+    let commit = git(config, &commit_args, None, Some(author))?;
+    // Refused if the branch moved since it was read (eg someone committed by hand), rather than dropping their commit.
+    // The zero object name only lets the branch be created
+    let branch = format!("refs/heads/{}", config.branch);
+    let expected = branch_tip.unwrap_or_else(|| "0".repeat(commit.len()));
+    git(config, &["update-ref", "-m", message, &branch, &commit, &expected], None, None)
+        .map_err(|e| format!("the {} branch moved during the commit, try again: {e}", config.branch))?;
+
+    // When the branch is checked out, its index must follow the commit or git status would show it reverted
+    if git(config, &["symbolic-ref", "--quiet", "HEAD"], None, None).ok().as_deref() == Some(branch.as_str()) {
+        let mut reset_args = vec!["reset".to_string(), "--quiet".to_string(), "--".to_string()];
+        for path in paths {
+            reset_args.push(absolute(path)?);
+        }
+        git(config, &reset_args.iter().map(String::as_str).collect::<Vec<&str>>(), None, None)?;
+    }
+    Ok(Some(commit))
+}
+
+// Commits of the content branch changing a path, most recent first
+pub fn history(config: &GitConfig, path: &Path) -> Result<Vec<Revision>, String> {
+    let tip = match tip(config) {
+        Some(tip) => tip,
+        None => return Ok(Vec::new()),
+    };
+    let max_count = format!("--max-count={MAX_REVISIONS}");
+    let pathspec = absolute(path)?;
+    let log = git(config, &["log", &max_count, "--format=%H%x1f%an%x1f%ae%x1f%aI%x1f%s", &tip, "--", &pathspec], None, None)?;
+    Ok(log.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\x1f').map(str::to_string);
+            Some(Revision {
+                commit: fields.next()?,
+                author: fields.next()?,
+                email: fields.next()?,
+                date: fields.next()?,
+                message: fields.next()?,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Repository of a test in the temporary folder, with a first commit on main, removed at the end
+    struct Repository(GitConfig);
+
+    impl Repository {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("orailix-git-{}-{name}", std::process::id()));
+            fs::create_dir_all(path.join("orailix.com")).unwrap();
+            fs::write(path.join("orailix.com/index.html"), "<html></html>\n").unwrap();
+            let repository = Self(GitConfig { repository: path.to_string_lossy().into_owned(), ..GitConfig::default() });
+            repository.git(&["init", "--quiet", "--initial-branch=main"]);
+            repository.git(&["add", "--all"]);
+            repository.git(&["-c", "user.name=Maintainer", "-c", "user.email=maintainer@orailix.com", "commit", "--quiet", "-m", "Website"]);
+            repository
+        }
+
+        fn git(&self, args: &[&str]) -> String {
+            git(&self.0, args, None, None).unwrap()
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            Path::new(&self.0.repository).join(path)
+        }
+    }
+
+    impl Drop for Repository {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.repository);
+        }
+    }
+
+    #[test]
+    fn commits_on_the_content_branch() {
+        let repository = Repository::new("commit");
+        let main = repository.git(&["rev-parse", "HEAD"]);
+        let article = repository.path("orailix.com/news/talks/2024-14-08-talk");
+        let author = Author { name: "Alice A".to_string(), email: "alice@orailix.com".to_string() };
+        fs::create_dir_all(&article).unwrap();
+        fs::write(article.join("manifest.txt"), "title=Talk\ndate=2024-14-08\n").unwrap();
+
+        // The branch is created from HEAD
+        let created = commit(&repository.0, std::slice::from_ref(&article), &author, "Create article talks/2024-14-08-talk").unwrap().unwrap();
+        assert_eq!(repository.git(&["rev-parse", "content"]), created);
+        assert_eq!(repository.git(&["rev-parse", "content^"]), main);
+        assert_eq!(repository.git(&["log", "-1", "--format=%an <%ae>, %cn <%ce>", "content"]), "Alice A <alice@orailix.com>, ORAILIX backend <backend@orailix.com>");
+        assert_eq!(repository.git(&["ls-tree", "-r", "--name-only", "content", "orailix.com/news"]), "orailix.com/news/talks/2024-14-08-talk/manifest.txt");
+        // Neither the checked out branch nor its index changed
+        assert_eq!(repository.git(&["rev-parse", "HEAD"]), main);
+        assert_eq!(repository.git(&["diff", "--cached", "--name-only"]), "");
+
+        assert_eq!(commit(&repository.0, std::slice::from_ref(&article), &author, "Update article talks/2024-14-08-talk").unwrap(), None);
+        fs::write(article.join("manifest.txt"), "title=Talk, updated\ndate=2024-14-08\n").unwrap();
+        let updated = commit(&repository.0, std::slice::from_ref(&article), &author, "Update article talks/2024-14-08-talk").unwrap().unwrap();
+        assert_eq!(repository.git(&["rev-parse", "content^"]), created);
+        fs::remove_dir_all(&article).unwrap();
+        let deleted = commit(&repository.0, std::slice::from_ref(&article), &author, "Delete article talks/2024-14-08-talk").unwrap().unwrap();
+        assert_eq!(repository.git(&["ls-tree", "-r", "--name-only", "content", "orailix.com/news"]), "");
+
+        // Deleted articles keep their history
+        let revisions = history(&repository.0, &article).unwrap();
+        assert_eq!(revisions.iter().map(|revision| revision.commit.as_str()).collect::<Vec<_>>(), [&deleted, &updated, &created]);
+        assert_eq!((revisions[0].author.as_str(), revisions[0].email.as_str()), ("Alice A", "alice@orailix.com"));
+        assert_eq!(revisions[2].message, "Create article talks/2024-14-08-talk");
+        assert!(history(&repository.0, &repository.path("orailix.com/news/talks/other")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_checked_out_content_branch_follows_the_commits() {
+        let repository = Repository::new("checkout");
+        repository.git(&["checkout", "--quiet", "-b", "content"]);
+        let article = repository.path("orailix.com/news/talks/2024-14-08-talk");
+        fs::create_dir_all(&article).unwrap();
+        fs::write(article.join("manifest.txt"), "title=Talk\ndate=2024-14-08\n").unwrap();
+        let author = Author { name: "Alice A".to_string(), email: "alice@orailix.com".to_string() };
+        let created = commit(&repository.0, &[article], &author, "Create article talks/2024-14-08-talk").unwrap().unwrap();
+        assert_eq!(repository.git(&["rev-parse", "HEAD"]), created);
+        assert_eq!(repository.git(&["status", "--porcelain"]), "");
+    }
+}
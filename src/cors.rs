@@ -0,0 +1,171 @@
+use gotham::hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+};
+use gotham::hyper::{HeaderMap, Method};
+use crate::config::CorsConfig;
+
+// An entry of cors.allowed_origins
+#[derive(Debug, PartialEq)]
+enum OriginPattern {
+    // *, any origin
+    Any,
+    // eg https://orailix.com
+    Exact(String),
+    // eg https://*.orailix.com, any subdomain but not orailix.com itself
+    Subdomains { scheme: String, suffix: String },
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.trim().trim_end_matches('/').to_ascii_lowercase();
+        if pattern == "*" {
+            return Ok(OriginPattern::Any)
+        }
+        let (scheme, host) = pattern.split_once("://").ok_or(format!("invalid origin '{pattern}', expected eg https://orailix.com"))?;
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("invalid origin '{pattern}', an origin has no path"))
+        }
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => Ok(OriginPattern::Subdomains { scheme: scheme.to_string(), suffix: format!(".{domain}") }),
+            None if !host.contains('*') => Ok(OriginPattern::Exact(pattern.clone())),
+            _ => Err(format!("invalid origin '{pattern}', * only stands for the subdomains, as in https://*.orailix.com")),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(allowed) => origin == allowed,
+            OriginPattern::Subdomains { scheme, suffix } => origin.split_once("://").is_some_and(|(origin_scheme, host)| {
+                origin_scheme == scheme && host.len() > suffix.len() && host.ends_with(suffix.as_str())
+            }),
+        }
+    }
+}
+
+// Which browser origins may call the server, and with what, from the [cors] section of the configuration
+pub struct CorsPolicy {
+    origins: Vec<OriginPattern>,
+    methods: Vec<Method>,
+    // Lowercase, as header names are compared
+    headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: u64,
+}
+
+impl CorsPolicy {
+    // The origins of the configuration and of the command line, refusing those that cannot be matched
+    pub fn new(config: &CorsConfig, command_line_origins: &[String]) -> Result<Self, String> {
+        let origins = config.allowed_origins.iter().chain(command_line_origins)
+            .map(|origin| OriginPattern::parse(origin))
+            .collect::<Result<Vec<OriginPattern>, String>>()?;
+        // Browsers refuse credentials with a wildcard, and reflecting every origin with credentials would let any website act as the user
+        if config.allow_credentials && origins.contains(&OriginPattern::Any) {
+            return Err("cors.allowed_origins cannot contain * when cors.allow_credentials is true".to_string())
+        }
+        let methods = config.allowed_methods.iter()
+            .map(|method| method.to_ascii_uppercase().parse::<Method>().map_err(|_| format!("invalid method '{method}'")))
+            .collect::<Result<Vec<Method>, String>>()?;
+        Ok(Self {
+            origins,
+            methods,
+            headers: config.allowed_headers.iter().map(|header| header.trim().to_ascii_lowercase()).collect(),
+            allow_credentials: config.allow_credentials,
+            max_age: config.max_age,
+        })
+    }
+
+    // Access-Control-Allow-Origin for a request origin, None when it is not allowed
+    fn allow_origin(&self, origin: &str) -> Option<HeaderValue> {
+        let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+        let pattern = self.origins.iter().find(|pattern| pattern.matches(&origin))?;
+        match pattern {
+            OriginPattern::Any => Some(HeaderValue::from_static("*")),
+            _ => HeaderValue::from_str(&origin).ok(),
+        }
+    }
+
+    // Headers of a response to an allowed origin, none for the others
+    pub fn response_headers(&self, origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(allowed) = self.allow_origin(origin) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+            if self.allow_credentials {
+                headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+            }
+        }
+        headers
+    }
+
+    // Headers answering a preflight request, None when the origin, the method or one of the headers is not allowed
+    pub fn preflight_headers(&self, origin: &str, method: &str, request_headers: &str) -> Option<HeaderMap> {
+        let method = method.trim().parse::<Method>().ok()?;
+        if !self.methods.contains(&method) {
+            return None
+        }
+        let request_headers = request_headers.split(',').map(|header| header.trim().to_ascii_lowercase()).filter(|header| !header.is_empty());
+        if !request_headers.clone().all(|header| self.headers.contains(&header)) {
+            return None
+        }
+        let mut headers = self.response_headers(origin);
+        if headers.is_empty() {
+            return None
+        }
+        let methods = self.methods.iter().map(Method::as_str).collect::<Vec<&str>>().join(", ");
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods.parse().ok()?);
+        if !self.headers.is_empty() {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, self.headers.join(", ").parse().ok()?);
+        }
+        headers.insert(ACCESS_CONTROL_MAX_AGE, self.max_age.into());
+        Some(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], allow_credentials: bool) -> Result<CorsPolicy, String> {
+        let config = CorsConfig { allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(), allow_credentials, ..CorsConfig::default() };
+        CorsPolicy::new(&config, &[])
+    }
+
+    #[test]
+    fn matches_exact_origins_and_subdomains() {
+        let policy = policy(&["https://orailix.com/", "https://*.lix.polytechnique.fr", "http://localhost:8080"], true).unwrap();
+        assert_eq!(policy.allow_origin("https://orailix.com").unwrap(), "https://orailix.com");
+        assert_eq!(policy.allow_origin("https://www.lix.polytechnique.fr").unwrap(), "https://www.lix.polytechnique.fr");
+        assert_eq!(policy.allow_origin("https://a.b.lix.polytechnique.fr").unwrap(), "https://a.b.lix.polytechnique.fr");
+        assert!(policy.allow_origin("http://localhost:8080").is_some());
+        assert!(policy.allow_origin("http://orailix.com").is_none());
+        assert!(policy.allow_origin("https://lix.polytechnique.fr").is_none());
+        assert!(policy.allow_origin("https://evillix.polytechnique.fr").is_none());
+        assert!(policy.allow_origin("https://orailix.com.evil.com").is_none());
+        assert!(policy.allow_origin("http://localhost:8081").is_none());
+        assert!(policy.allow_origin("null").is_none());
+    }
+
+    #[test]
+    fn refuses_invalid_patterns() {
+        assert!(policy(&["orailix.com"], false).is_err());
+        assert!(policy(&["https://orailix.com/news"], false).is_err());
+        assert!(policy(&["https://*"], false).is_err());
+        assert!(policy(&["https://a.*.com"], false).is_err());
+        assert!(policy(&["*"], true).is_err());
+        assert_eq!(policy(&["*"], false).unwrap().allow_origin("https://example.com").unwrap(), "*");
+    }
+
+    #[test]
+    fn answers_preflights_of_allowed_requests_only() {
+        let policy = policy(&["https://orailix.com"], true).unwrap();
+        let headers = policy.preflight_headers("https://orailix.com", "PUT", "Content-Type, x-csrf-token").unwrap();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://orailix.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST, PUT, DELETE");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(policy.preflight_headers("https://orailix.com", "PATCH", "").is_none());
+        assert!(policy.preflight_headers("https://orailix.com", "POST", "X-Unknown").is_none());
+        assert!(policy.preflight_headers("https://example.com", "POST", "").is_none());
+    }
+}
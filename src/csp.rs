@@ -0,0 +1,204 @@
+use std::pin::Pin;
+use data_encoding::BASE64;
+use futures_util::FutureExt;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_empty_response;
+use gotham::hyper::body::HttpBody;
+use gotham::hyper::{Body, StatusCode};
+use gotham::state::{FromState, State};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::Value;
+use crate::config::CspConfig;
+use crate::logging;
+use tracing::warn;
+
+pub const REPORT_ROUTE: &str = "/api/csp-report";
+// Reports are small, anything larger is not one
+const MAX_REPORT_SIZE: usize = 64 * 1024;
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+// Fields of the reports are logged up to this many characters, anyone can send a report
+const MAX_FIELD_LENGTH: usize = 256;
+
+// Content-Security-Policy of the responses, with a nonce of its own for every response
+pub struct ContentSecurityPolicy {
+    pub header: &'static str,
+    policy: String,
+}
+
+impl ContentSecurityPolicy {
+    pub fn new(config: &CspConfig) -> Option<Self> {
+        if !config.enabled || config.policy.is_empty() {
+            return None
+        }
+        let header = match config.report_only {
+            true => "Content-Security-Policy-Report-Only",
+            false => "Content-Security-Policy",
+        };
+        Some(Self { header, policy: config.policy.clone() })
+    }
+
+    // The policy allowing the inline scripts carrying the nonce
+    pub fn policy(&self, nonce: &str) -> String {
+        self.policy.replace(NONCE_PLACEHOLDER, nonce)
+    }
+}
+
+// 128 random bits, in base64 as in the policy
+pub fn generate_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64.encode(&bytes)
+}
+
+// Add the nonce to the inline <script> tags of a page, those loading a src are allowed by their origin
+pub fn inject_nonce(html: &str, nonce: &str) -> String {
+    let mut page = String::with_capacity(html.len() + 64);
+    let mut rest = html;
+    while let Some(start) = find_script_tag(rest) {
+        let tag_end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = &rest[start..tag_end];
+        let attributes = tag[7..].to_ascii_lowercase();
+        let has_attribute = |name: &str| attributes.split(|c: char| c.is_ascii_whitespace()).any(|attribute| attribute == name || attribute.starts_with(&format!("{name}=")));
+        page.push_str(&rest[..start + 7]);
+        if !has_attribute("src") && !has_attribute("nonce") {
+            page.push_str(&format!(" nonce=\"{nonce}\""));
+        }
+        rest = &rest[start + 7..];
+    }
+    page.push_str(rest);
+    page
+}
+
+// Start of the next <script tag, not of a <scripts or <script-like element
+fn find_script_tag(html: &str) -> Option<usize> {
+    let bytes = html.as_bytes();
+    (0..bytes.len().saturating_sub(7)).find(|start| {
+        bytes[*start..*start + 7].eq_ignore_ascii_case(b"<script") && matches!(bytes[*start + 7], b'>' | b' ' | b'\t' | b'\n' | b'\r' | b'/')
+    })
+}
+
+// A reported violation: the directive, what it blocked, and where.
+// The fields are escaped and truncated so that a forged report cannot forge log lines
+#[derive(Debug, PartialEq)]
+struct Violation {
+    directive: String,
+    blocked: String,
+    document: String,
+    source: String,
+}
+
+fn describe(report: &Value) -> Option<Violation> {
+    let field = |names: &[&str]| {
+        let value = names.iter().find_map(|name| report.get(name).and_then(Value::as_str)).unwrap_or("-");
+        logging::escape(&value.chars().take(MAX_FIELD_LENGTH).collect::<String>())
+    };
+    if !report.is_object() {
+        return None
+    }
+    let source = match (field(&["source-file", "sourceFile"]), report.get("line-number").or(report.get("lineNumber")).and_then(Value::as_u64)) {
+        (source, Some(line)) if source != "-" => format!("{source}:{line}"),
+        _ => "-".to_string(),
+    };
+    Some(Violation {
+        directive: field(&["effective-directive", "effectiveDirective", "violated-directive"]),
+        blocked: field(&["blocked-uri", "blockedURL"]),
+        document: field(&["document-uri", "documentURL"]),
+        source,
+    })
+}
+
+// Reports of the report-uri directive are a {"csp-report": {...}} object, those of the Reporting API
+// an array of {"type": "csp-violation", "body": {...}}
+fn parse_reports(body: &[u8]) -> Vec<Violation> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(report)) => report.get("csp-report").and_then(describe).into_iter().collect(),
+        Ok(Value::Array(reports)) => reports.iter().filter_map(|report| report.get("body")).filter_map(describe).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// The body of a request, None when larger than a report can be
+async fn read_report(mut body: Body) -> Result<Option<Vec<u8>>, gotham::hyper::Error> {
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if content.len() + chunk.len() > MAX_REPORT_SIZE {
+            return Ok(None)
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(Some(content))
+}
+
+// POST /api/csp-report: log the violations reported by the browsers. They send them on their own,
+// without CSRF token, see CsrfMiddleware
+pub fn csp_report_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let f = async move {
+        let status = match read_report(Body::take_from(&mut state)).await {
+            Ok(Some(body)) => {
+                let reports = parse_reports(&body);
+                for report in &reports {
+                    warn!(directive = %report.directive, blocked = %report.blocked, document = %report.document, source = %report.source, "csp violation");
+                }
+                match reports.is_empty() {
+                    true => StatusCode::BAD_REQUEST,
+                    false => StatusCode::NO_CONTENT,
+                }
+            }
+            Ok(None) => StatusCode::PAYLOAD_TOO_LARGE,
+            Err(e) => return Err((state, e.into())),
+        };
+        let res = create_empty_response(&state, status);
+        Ok((state, res))
+    };
+    f.boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_the_nonce_to_inline_scripts_only() {
+        let html = r#"<script>a()</script><SCRIPT type="text/javascript">b()</SCRIPT><script src="/c.js"></script><script nonce="x">d()</script><scripts>"#;
+        assert_eq!(
+            inject_nonce(html, "n0nce"),
+            r#"<script nonce="n0nce">a()</script><SCRIPT nonce="n0nce" type="text/javascript">b()</SCRIPT><script src="/c.js"></script><script nonce="x">d()</script><scripts>"#
+        );
+        assert_eq!(inject_nonce("<p>no script</p><script", "n0nce"), "<p>no script</p><script");
+    }
+
+    #[test]
+    fn parses_both_report_formats() {
+        let legacy = br#"{"csp-report":{"document-uri":"https://orailix.com/","violated-directive":"script-src-elem","blocked-uri":"inline","source-file":"https://orailix.com/","line-number":12}}"#;
+        assert_eq!(parse_reports(legacy), [Violation {
+            directive: "script-src-elem".to_string(),
+            blocked: "inline".to_string(),
+            document: "https://orailix.com/".to_string(),
+            source: "https://orailix.com/:12".to_string(),
+        }]);
+
+        let reporting_api = br#"[{"type":"csp-violation","body":{"documentURL":"https://orailix.com/news/","effectiveDirective":"img-src","blockedURL":"http://tracker.example/p.gif"}}]"#;
+        assert_eq!(parse_reports(reporting_api), [Violation {
+            directive: "img-src".to_string(),
+            blocked: "http://tracker.example/p.gif".to_string(),
+            document: "https://orailix.com/news/".to_string(),
+            source: "-".to_string(),
+        }]);
+
+        assert!(parse_reports(b"not a report").is_empty());
+    }
+
+    #[test]
+    fn escapes_and_truncates_the_reported_fields() {
+        let forged = format!(r#"{{"csp-report":{{"blocked-uri":"x\n2026-10-19T00:00:00Z  INFO login of admin","document-uri":"{}"}}}}"#, "a".repeat(1000));
+        let violation = &parse_reports(forged.as_bytes())[0];
+        assert_eq!(violation.blocked, "x\\x0a2026-10-19T00:00:00Z  INFO login of admin");
+        assert_eq!(violation.document.len(), MAX_FIELD_LENGTH);
+        assert_eq!(violation.directive, "-");
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use futures_util::{future, FutureExt};
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::SET_COOKIE;
+use gotham::hyper::{HeaderMap, Method, StatusCode, Uri};
+use gotham::middleware::cookie::CookieParser;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{FromState, State};
+use gotham_derive::StateData;
+use hmac::{Hmac, Mac};
+use mime::APPLICATION_JSON;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::Sha256;
+use crate::api_tokens::TokenLogin;
+use crate::csp;
+use crate::session_management::{current_login, session_identifier, SessionStore};
+use crate::users;
+
+// Signed double-submit tokens: the token is sent both in a cookie and in this header, and is signed
+// together with the session it was issued for, so that it is worthless in another session
+const CSRF_COOKIE: &str = "csrf_token";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// Key signing the tokens, kept in a file so that the tokens survive restarts like the sessions
+#[derive(Clone, StateData)]
+pub struct CsrfKey {
+    key: Arc<Vec<u8>>,
+}
+
+impl CsrfKey {
+    // Read the key, creating it on first start
+    pub fn load(path: &str) -> Result<Self, String> {
+        let key = match fs::read(path) {
+            Ok(key) if key.len() >= 32 => key,
+            _ => {
+                let mut key = vec![0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                if let Some(folder) = Path::new(path).parent().filter(|folder| !folder.as_os_str().is_empty()) {
+                    fs::create_dir_all(folder).map_err(|e| format!("cannot create {}: {e}", folder.display()))?;
+                }
+                users::write_private(Path::new(path), &key).map_err(|e| format!("cannot write the CSRF key {path}: {e}"))?;
+                key
+            }
+        };
+        Ok(Self { key: Arc::new(key) })
+    }
+
+    fn mac(&self, nonce: &str, binding: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(nonce.as_bytes());
+        mac.update(b"|");
+        mac.update(binding.as_bytes());
+        mac
+    }
+
+    // <nonce>.<signature>, in hexadecimal
+    fn token(&self, binding: &str) -> String {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let nonce = hex(&nonce);
+        let signature = hex(&self.mac(&nonce, binding).finalize().into_bytes());
+        format!("{nonce}.{signature}")
+    }
+
+    fn is_valid(&self, token: &str, binding: &str) -> bool {
+        let (nonce, signature) = match token.split_once('.') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        match unhex(signature) {
+            Some(signature) => self.mac(nonce, binding).verify_slice(&signature).is_ok(),
+            None => false,
+        }
+    }
+}
+
+// Logged in tokens are bound to the session identifier, which changes on login.
+// Anonymous ones only protect the login itself and rely on the double-submit cookie
+fn binding(state: &State) -> String {
+    match current_login(state) {
+        Some(_) => session_identifier(state).unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+// New token for the current session, with the Set-Cookie carrying it
+pub fn issue_token(state: &State) -> (String, String) {
+    token_for(state, &binding(state))
+}
+
+// New token for a given session identifier, eg the one created by a login
+pub fn token_for(state: &State, session_identifier: &str) -> (String, String) {
+    let token = CsrfKey::borrow_from(state).token(session_identifier);
+    let cookie = SessionStore::borrow_from(state).cookie(CSRF_COOKIE, &token);
+    (token, cookie)
+}
+
+#[derive(Serialize)]
+struct CsrfToken {
+    csrf_token: String,
+}
+
+// Token to send in the X-CSRF-Token header of the POST, PUT, PATCH and DELETE requests
+pub fn csrf_token_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let (csrf_token, cookie) = issue_token(&state);
+    let response_payload = serde_json::to_string(&CsrfToken { csrf_token }).unwrap_or_default();
+    let mut res = create_response(&state, StatusCode::OK, APPLICATION_JSON, response_payload);
+    res.headers_mut().append(SET_COOKIE, cookie.parse().unwrap());
+    res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+    future::ok((state, res)).boxed()
+}
+
+// Refuses the requests changing something without a valid token, safe methods are not checked
+#[derive(Clone)]
+pub struct CsrfMiddleware {
+    key: CsrfKey,
+}
+
+impl CsrfMiddleware {
+    pub fn new(key: CsrfKey) -> Self {
+        Self { key }
+    }
+}
+
+impl NewMiddleware for CsrfMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[derive(Serialize)]
+struct CsrfError {
+    success: bool,
+    error: &'static str,
+}
+
+impl Middleware for CsrfMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let is_safe = matches!(*Method::borrow_from(&state), Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE);
+        // Browsers never send an Authorization header on their own, requests authenticated by a token cannot be forged
+        let is_token = TokenLogin::try_borrow_from(&state).is_some();
+        // Browsers send the CSP violation reports on their own, they change nothing but the logs
+        let is_exempt = Uri::borrow_from(&state).path() == csp::REPORT_ROUTE;
+        let is_valid = is_safe || is_token || is_exempt || {
+            let header = HeaderMap::borrow_from(&state).get(CSRF_HEADER).and_then(|token| token.to_str().ok());
+            let cookie = CookieParser::from_state(&state).get(CSRF_COOKIE).map(|cookie| cookie.value().to_string());
+            match (header, cookie) {
+                (Some(header), Some(cookie)) => header == cookie && self.key.is_valid(header, &binding(&state)),
+                _ => false,
+            }
+        };
+
+        state.put(self.key);
+        match is_valid {
+            true => chain(state),
+            false => {
+                let response_payload = serde_json::to_string(&CsrfError { success: false, error: "missing or invalid CSRF token" }).unwrap_or_default();
+                let res = create_response(&state, StatusCode::FORBIDDEN, APPLICATION_JSON, response_payload);
+                future::ok((state, res)).boxed()
+            }
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_key() -> CsrfKey {
+        let mut key = vec![0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        CsrfKey { key: Arc::new(key) }
+    }
+
+    #[test]
+    fn tokens_are_bound_to_their_session() {
+        let key = random_key();
+        let token = key.token("session-a");
+        assert!(key.is_valid(&token, "session-a"));
+        assert!(!key.is_valid(&token, "session-b"));
+        assert!(!key.is_valid(&token, ""));
+        assert!(!random_key().is_valid(&token, "session-a"));
+        assert!(key.is_valid(&key.token(""), ""));
+    }
+
+    #[test]
+    fn refuses_the_tampered_tokens() {
+        let key = random_key();
+        let token = key.token("session-a");
+        let (nonce, signature) = token.split_once('.').unwrap();
+        let flipped = |text: &str| match text.strip_prefix('0') {
+            Some(rest) => format!("1{rest}"),
+            None => format!("0{}", &text[1..]),
+        };
+        assert!(!key.is_valid(&format!("{}.{signature}", flipped(nonce)), "session-a"));
+        assert!(!key.is_valid(&format!("{nonce}.{}", flipped(signature)), "session-a"));
+        assert!(!key.is_valid(&format!("{nonce}.{}", &signature[..signature.len() - 2]), "session-a"));
+        assert!(!key.is_valid(&format!("{nonce}.{signature}0"), "session-a"));
+        assert!(!key.is_valid(nonce, "session-a"));
+        assert!(!key.is_valid(&format!("{nonce}.{}", "z".repeat(signature.len())), "session-a"));
+    }
+
+    #[test]
+    fn keeps_the_key_across_restarts() {
+        let path = std::env::temp_dir().join(format!("orailix-csrf-{}", std::process::id())).join("csrf.key");
+        let path = path.to_string_lossy();
+        let token = CsrfKey::load(&path).unwrap().token("session-a");
+        assert!(CsrfKey::load(&path).unwrap().is_valid(&token, "session-a"));
+        fs::remove_dir_all(Path::new(&*path).parent().unwrap()).unwrap();
+
+        // The folder of the key is a file
+        let file = std::env::temp_dir().join(format!("orailix-csrf-file-{}", std::process::id()));
+        fs::write(&file, "").unwrap();
+        assert!(CsrfKey::load(&file.join("csrf.key").to_string_lossy()).is_err());
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+use futures_util::FutureExt;
+use gotham::anyhow;
+use gotham::handler::{Handler, HandlerFuture, IntoResponse, NewHandler};
+use gotham::hyper::header::{
+    HeaderName, HeaderValue, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_LENGTH, CONTENT_TYPE, DATE, ORIGIN, VARY,
+};
+use gotham::hyper::{body, Body, HeaderMap, Method, Response, StatusCode};
+use gotham::helpers::http::response::create_empty_response;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::router::Router;
+use gotham::state::{FromState, State};
+use crate::config::{CorsConfig, CspConfig, HeadersConfig, HstsConfig};
+use crate::cors::CorsPolicy;
+use crate::csp::{self, ContentSecurityPolicy};
+
+// Security and CORS headers added to every response, from the [headers], [csp] and [cors] sections of the configuration
+#[derive(Clone)]
+pub struct HeadersMiddleware {
+    headers: Arc<HeaderMap>,
+    csp: Option<Arc<ContentSecurityPolicy>>,
+    cors: Arc<CorsPolicy>,
+}
+
+impl HeadersMiddleware {
+    // Fails on a header name or value HTTP does not allow, so that a typo is noticed at startup.
+    // HSTS is only sent by a server with TLS, browsers ignore it over HTTP
+    pub fn new(config: &HeadersConfig, csp_config: &CspConfig, cors_config: &CorsConfig, origins: &[String], tls: bool) -> Result<Self, String> {
+        let hsts = strict_transport_security(&config.hsts)?;
+        let mut headers = HeaderMap::new();
+        let mut add = |name: &str, value: &str| -> Result<(), String> {
+            // An empty value leaves the header out
+            if value.is_empty() {
+                return Ok(())
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name '{name}'"))?;
+            let value = HeaderValue::from_str(value).map_err(|_| format!("invalid value '{value}' for header {name}"))?;
+            headers.insert(name, value);
+            Ok(())
+        };
+        if tls {
+            add("Strict-Transport-Security", &hsts)?;
+        }
+        add("X-Frame-Options", &config.frame_options)?;
+        add("X-Content-Type-Options", &config.content_type_options)?;
+        add("Permissions-Policy", &config.permissions_policy)?;
+        // The CORS headers depend on the Origin of the request
+        add("Vary", "Origin")?;
+        for (name, value) in &config.extra {
+            add(name, value)?;
+        }
+        let csp = ContentSecurityPolicy::new(csp_config);
+        if let Some(csp) = &csp {
+            HeaderValue::from_str(&csp.policy(&csp::generate_nonce())).map_err(|_| "invalid CSP policy".to_string())?;
+        }
+        let cors = CorsPolicy::new(cors_config, origins)?;
+        Ok(Self { headers: Arc::new(headers), csp: csp.map(Arc::new), cors: Arc::new(cors) })
+    }
+
+    // Wrap the router, so that its own responses (eg 404 on unknown routes, 405) get the headers too
+    pub fn around(self, router: Router) -> WithHeaders {
+        WithHeaders { middleware: self, router }
+    }
+
+    // Headers set by the handler are kept, except Vary which is completed
+    fn apply(&self, res: &mut Response<Body>) {
+        let headers = res.headers_mut();
+        for (name, value) in self.headers.iter() {
+            match (name == VARY, headers.get(name)) {
+                (true, Some(vary)) => {
+                    let vary = vary.to_str().unwrap_or_default();
+                    if !vary.split(',').any(|field| field.trim().eq_ignore_ascii_case(value.to_str().unwrap_or_default())) {
+                        let merged = format!("{vary}, {}", value.to_str().unwrap_or_default());
+                        headers.insert(VARY, merged.parse().unwrap_or(value.clone()));
+                    }
+                }
+                (_, Some(_)) => (),
+                (_, None) => {
+                    headers.insert(name, value.clone());
+                }
+            }
+        }
+        headers.insert(DATE, httpdate::fmt_http_date(SystemTime::now()).parse().unwrap());
+    }
+}
+
+// Value of Strict-Transport-Security, empty when disabled. Preload lists refuse a policy without the subdomains or of less than a year
+fn strict_transport_security(config: &HstsConfig) -> Result<String, String> {
+    if !config.enabled {
+        return Ok(String::new())
+    }
+    if config.preload && (!config.include_subdomains || config.max_age < 31536000) {
+        return Err("headers.hsts.preload needs include_subdomains and a max_age of 31536000 at least".to_string())
+    }
+    let mut value = format!("max-age={}", config.max_age);
+    if config.include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+    if config.preload {
+        value.push_str("; preload");
+    }
+    Ok(value)
+}
+
+impl NewMiddleware for HeadersMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl Middleware for HeadersMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let request_headers = HeaderMap::borrow_from(&state);
+        let header = |name| request_headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok()).map(str::to_string);
+        let origin = header(ORIGIN);
+        // A preflight is answered here, the routes do not know OPTIONS
+        if let (Some(origin), Some(method), true) = (&origin, header(ACCESS_CONTROL_REQUEST_METHOD), Method::borrow_from(&state) == Method::OPTIONS) {
+            let preflight = self.cors.preflight_headers(origin, &method, &header(ACCESS_CONTROL_REQUEST_HEADERS).unwrap_or_default());
+            let mut res = match preflight {
+                Some(cors_headers) => {
+                    let mut res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                    res.headers_mut().extend(cors_headers);
+                    res
+                }
+                None => create_empty_response(&state, StatusCode::FORBIDDEN),
+            };
+            self.apply(&mut res);
+            return Box::pin(async move { Ok((state, res)) })
+        }
+        chain(state)
+            .then(move |result| async move {
+                // Handler errors are turned into their response here rather than by the router, to get the headers
+                let (state, mut res) = match result {
+                    Ok((state, res)) => (state, res),
+                    Err((state, e)) => {
+                        let res = e.into_response(&state);
+                        (state, res)
+                    }
+                };
+                self.apply(&mut res);
+                if let Some(origin) = &origin {
+                    for (name, value) in self.cors.response_headers(origin) {
+                        if let Some(name) = name {
+                            res.headers_mut().entry(name).or_insert(value);
+                        }
+                    }
+                }
+                match &self.csp {
+                    Some(csp) => match with_policy(res, csp).await {
+                        Ok(res) => Ok((state, res)),
+                        Err(e) => Err((state, e.into())),
+                    },
+                    None => Ok((state, res)),
+                }
+            })
+            .boxed()
+    }
+}
+
+// Add the CSP with a new nonce, given to the inline scripts of HTML pages
+async fn with_policy(res: Response<Body>, csp: &ContentSecurityPolicy) -> Result<Response<Body>, gotham::hyper::Error> {
+    if res.headers().contains_key(csp.header) {
+        return Ok(res)
+    }
+    let nonce = csp::generate_nonce();
+    let is_html = res.headers().get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    let (mut parts, content) = res.into_parts();
+    let content = match is_html {
+        true => {
+            let page = body::to_bytes(content).await?;
+            match std::str::from_utf8(&page) {
+                Ok(page) => {
+                    let page = csp::inject_nonce(page, &nonce);
+                    parts.headers.insert(CONTENT_LENGTH, page.len().into());
+                    Body::from(page)
+                }
+                Err(_) => Body::from(page),
+            }
+        }
+        false => content,
+    };
+    parts.headers.insert(csp.header, csp.policy(&nonce).parse().unwrap());
+    Ok(Response::from_parts(parts, content))
+}
+
+// The router answering through the headers middleware
+#[derive(Clone)]
+pub struct WithHeaders {
+    middleware: HeadersMiddleware,
+    router: Router,
+}
+
+impl NewHandler for WithHeaders {
+    type Instance = Self;
+
+    fn new_handler(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl Handler for WithHeaders {
+    fn handle(self, state: State) -> Pin<Box<HandlerFuture>> {
+        let router = self.router;
+        self.middleware.call(state, move |state| router.handle(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use gotham::hyper::header::STRICT_TRANSPORT_SECURITY;
+    use super::*;
+
+    fn headers(config: &HeadersConfig, tls: bool) -> HeaderMap {
+        let middleware = HeadersMiddleware::new(config, &CspConfig::default(), &CorsConfig::default(), &[], tls).unwrap();
+        let mut res = Response::new(Body::empty());
+        middleware.apply(&mut res);
+        res.headers().clone()
+    }
+
+    // Directives of Strict-Transport-Security, lowercase, with their value if any
+    fn directives(hsts: &HeaderValue) -> Vec<(String, Option<String>)> {
+        hsts.to_str().unwrap().split(';')
+            .map(|directive| match directive.trim().split_once('=') {
+                Some((name, value)) => (name.to_ascii_lowercase(), Some(value.trim_matches('"').to_string())),
+                None => (directive.trim().to_ascii_lowercase(), None),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sends_an_http_date() {
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let headers = headers(&HeadersConfig::default(), false);
+        let date = headers[DATE].to_str().unwrap();
+        assert!(date.ends_with(" GMT") && date.len() == "Sun, 06 Nov 1994 08:49:37 GMT".len());
+        let date = httpdate::parse_http_date(date).unwrap();
+        assert!(date >= before && date <= SystemTime::now());
+    }
+
+    #[test]
+    fn sends_hsts_over_tls_only() {
+        let mut config = HeadersConfig::default();
+        assert!(!headers(&config, false).contains_key(STRICT_TRANSPORT_SECURITY));
+        assert_eq!(directives(&headers(&config, true)[STRICT_TRANSPORT_SECURITY]), [("max-age".to_string(), Some("63072000".to_string()))]);
+
+        config.hsts.include_subdomains = true;
+        config.hsts.preload = true;
+        let hsts = directives(&headers(&config, true)[STRICT_TRANSPORT_SECURITY]);
+        assert_eq!(hsts, [
+            ("max-age".to_string(), Some("63072000".to_string())),
+            ("includesubdomains".to_string(), None),
+            ("preload".to_string(), None),
+        ]);
+
+        config.hsts.enabled = false;
+        assert!(!headers(&config, true).contains_key(STRICT_TRANSPORT_SECURITY));
+    }
+
+    #[test]
+    fn refuses_preload_without_the_requirements() {
+        let mut config = HeadersConfig::default();
+        config.hsts.preload = true;
+        assert!(HeadersMiddleware::new(&config, &CspConfig::default(), &CorsConfig::default(), &[], true).is_err());
+        config.hsts.include_subdomains = true;
+        config.hsts.max_age = 86400;
+        assert!(HeadersMiddleware::new(&config, &CspConfig::default(), &CorsConfig::default(), &[], true).is_err());
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+use futures_util::FutureExt;
+use gotham::handler::{HandlerFuture, HandlerResult};
+use gotham::helpers::http::response::{create_empty_response, create_response};
+use gotham::hyper::{Body, HeaderMap, Response, StatusCode, Uri};
+use gotham::state::{FromState, State};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+use crate::articles::{article_picture_paths, is_unpublished};
+use crate::access;
+use crate::config::Config;
+use crate::users::Role;
+use tracing::{debug, error, info};
+
+// Resized variants are derived from the pictures of the website folder
+const CONTENT_ROOT: &str = "orailix.com";
+const THUMBNAILS_ROUTE: &str = "/thumbnails/";
+const IMG_ROUTE: &str = "/img/";
+pub const VARIANT_WIDTHS: [u32; 3] = [400, 800, 1200];
+const WEBP_QUALITY: f32 = 80.0;
+const AVIF_QUALITY: u8 = 70;
+const JPEG_QUALITY: u8 = 85;
+// AVIF encoding is slow, a fast speed still gives files much smaller than the original pictures
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VariantFormat {
+    Avif,
+    WebP,
+    Jpeg,
+    Png,
+}
+
+impl VariantFormat {
+    // In order of preference, browsers pick the first <source> they support
+    pub const PICTURE_SOURCES: [VariantFormat; 2] = [VariantFormat::Avif, VariantFormat::WebP];
+
+    fn extension(self) -> &'static str {
+        match self {
+            VariantFormat::Avif => "avif",
+            VariantFormat::WebP => "webp",
+            VariantFormat::Jpeg => "jpg",
+            VariantFormat::Png => "png",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "avif" => Some(VariantFormat::Avif),
+            "webp" => Some(VariantFormat::WebP),
+            "jpg" | "jpeg" => Some(VariantFormat::Jpeg),
+            "png" => Some(VariantFormat::Png),
+            _ => None,
+        }
+    }
+
+    fn mime(self) -> Mime {
+        match self {
+            VariantFormat::Jpeg => mime::IMAGE_JPEG,
+            VariantFormat::Png => mime::IMAGE_PNG,
+            _ => format!("image/{}", self.extension()).parse().unwrap(),
+        }
+    }
+}
+
+// How a picture is fitted in the requested box when both a width and a height are given
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fit {
+    // Scaled to fit inside the box, keeping its aspect ratio
+    Contain,
+    // Scaled to fill the box, the overflow is cropped around the center
+    Cover,
+    // Stretched to the exact box
+    Fill,
+}
+
+impl Fit {
+    fn name(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Fit::Contain, Fit::Cover, Fit::Fill].into_iter().find(|fit| fit.name() == name)
+    }
+}
+
+// A derived picture: resized (never upscaled when contained), cropped and transcoded
+#[derive(Clone, Copy)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: VariantFormat,
+}
+
+impl Transform {
+    fn thumbnail(width: u32, format: VariantFormat) -> Self {
+        Transform { width: Some(width), height: None, fit: Fit::Contain, format }
+    }
+
+    // Cache folder of the pictures derived with this transform, eg w400 or w200-h200-cover
+    fn cache_key(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(width) = self.width {
+            parts.push(format!("w{width}"));
+        }
+        if let Some(height) = self.height {
+            parts.push(format!("h{height}"));
+        }
+        if self.width.is_some() && self.height.is_some() && self.fit != Fit::Contain {
+            parts.push(self.fit.name().to_string());
+        }
+        match parts.is_empty() {
+            true => "original".to_string(),
+            false => parts.join("-"),
+        }
+    }
+
+    fn apply(&self, picture: DynamicImage) -> DynamicImage {
+        match (self.width, self.height, self.fit) {
+            (None, None, _) => picture,
+            (Some(width), Some(height), Fit::Cover) => picture.resize_to_fill(width, height, FilterType::Lanczos3),
+            (Some(width), Some(height), Fit::Fill) => picture.resize_exact(width, height, FilterType::Lanczos3),
+            (width, height, _) => {
+                let width = width.unwrap_or(u32::MAX).min(picture.width());
+                let height = height.unwrap_or(u32::MAX).min(picture.height());
+                match width < picture.width() || height < picture.height() {
+                    true => picture.resize(width, height, FilterType::Lanczos3),
+                    false => picture,
+                }
+            }
+        }
+    }
+}
+
+// A <source> of a <picture> element
+#[derive(Serialize)]
+pub struct PictureSource {
+    #[serde(rename = "type")]
+    pub mime_type: String,
+    pub srcset: String,
+}
+
+// Deterministic URL of a variant, eg /thumbnails/400/news/tech/2025-05-09-1-year/image.webp.avif
+pub fn variant_url(site_path: &str, width: u32, format: VariantFormat) -> String {
+    format!("{THUMBNAILS_ROUTE}{width}/{}.{}", encode_path(site_path), format.extension())
+}
+
+// Percent-encode every segment of a path, so that names with spaces or commas stay one URL in a srcset
+fn encode_path(site_path: &str) -> String {
+    site_path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+// The path of a request as written on disk, None when it is not valid percent-encoded UTF-8
+fn decode_path(uri_path: &str) -> Option<String> {
+    let bytes = uri_path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok().filter(|hex| hex.bytes().all(|c| c.is_ascii_hexdigit()))?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// srcset of every variant format for a picture of the website, pictures are never upscaled
+pub fn picture_sources(site_path: &str, intrinsic_width: u32) -> Vec<PictureSource> {
+    let widths: Vec<u32> = VARIANT_WIDTHS.into_iter().filter(|width| *width < intrinsic_width).collect();
+    if widths.is_empty() {
+        return Vec::new()
+    }
+    VariantFormat::PICTURE_SOURCES.into_iter()
+        .map(|format| PictureSource {
+            mime_type: format.mime().to_string(),
+            srcset: widths.iter()
+                .map(|width| format!("{} {width}w", variant_url(site_path, *width, format)))
+                .collect::<Vec<String>>()
+                .join(", "),
+        })
+        .collect()
+}
+
+// Width and height of a picture of the website, read from its header only
+pub fn picture_dimensions(site_path: &str) -> Option<(u32, u32)> {
+    image::image_dimensions(content_path(site_path)?).ok()
+}
+
+// Generate the missing variants of every article picture in the background, so that visitors rarely wait for them
+pub fn pregenerate_article_variants(cache_dir: String) {
+    std::thread::spawn(move || {
+        for site_path in article_picture_paths() {
+            let width = match picture_dimensions(&site_path) {
+                Some((width, _)) => width,
+                None => continue,
+            };
+            for variant_width in VARIANT_WIDTHS.into_iter().filter(|variant_width| *variant_width < width) {
+                for format in VariantFormat::PICTURE_SOURCES {
+                    if let Err((_, e)) = cached_variant(&cache_dir, &site_path, Transform::thumbnail(variant_width, format)) {
+                        error!("{e}");
+                    }
+                }
+            }
+        }
+        info!("article picture variants are up to date");
+    });
+}
+
+// Serve a variant of an article picture, generating and caching it on the first request
+pub fn thumbnail_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let uri_path = Uri::borrow_from(&state).path().to_string();
+    let request = parse_variant_path(&uri_path).ok_or((StatusCode::NOT_FOUND, format!("{uri_path} is not a valid thumbnail")));
+    serve_variant(state, request).boxed()
+}
+
+// Parameters of /img/<site path>?w=&h=&fit=&format=
+#[derive(Deserialize)]
+struct ImageQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<String>,
+    format: Option<String>,
+}
+
+// Serve any picture of the website resized, cropped or transcoded, only to the sizes allowed in the configuration
+pub fn image_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let uri = Uri::borrow_from(&state);
+    let uri_path = uri.path().strip_prefix(IMG_ROUTE).unwrap_or_default();
+    let query = uri.query().unwrap_or_default().to_string();
+    let allowed_sizes = &Config::borrow_from(&state).images.allowed_sizes;
+    let request = decode_path(uri_path)
+        .ok_or((StatusCode::NOT_FOUND, format!("{uri_path} is not a valid picture path")))
+        .and_then(|site_path| parse_image_query(&site_path, &query, allowed_sizes).map(|transform| (site_path, transform)));
+    serve_variant(state, request).boxed()
+}
+
+type VariantRequest = Result<(String, Transform), (StatusCode, String)>;
+
+// Answer with a derived picture, or with 304 when the browser already has its current version
+async fn serve_variant(state: State, request: VariantRequest) -> HandlerResult {
+    let (site_path, transform) = match request {
+        Ok(request) => request,
+        Err(e) => return Ok(error_response(state, e)),
+    };
+    if is_reserved(&site_path, access::is_granted(&state, Role::Member), access::is_granted(&state, Role::Editor)) {
+        return Ok(error_response(state, (StatusCode::NOT_FOUND, format!("{site_path} is not available"))))
+    }
+    let (_, modified) = match source_file(&site_path) {
+        Ok(source) => source,
+        Err(e) => return Ok(error_response(state, e)),
+    };
+    let etag = variant_etag(&site_path, &transform, modified);
+    let max_age = Config::borrow_from(&state).images.max_age;
+
+    let mut res = match is_not_modified(HeaderMap::borrow_from(&state), &etag, modified) {
+        true => create_empty_response(&state, StatusCode::NOT_MODIFIED),
+        false => {
+            let cache_dir = Config::borrow_from(&state).images.cache_dir.clone();
+            // Decoding and encoding pictures is CPU bound, we keep it away from the server's threads
+            let variant = tokio::task::spawn_blocking(move || cached_variant(&cache_dir, &site_path, transform))
+                .await
+                .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())));
+            match variant {
+                Ok(variant) => create_response(&state, StatusCode::OK, transform.format.mime(), variant),
+                Err(e) => return Ok(error_response(state, e)),
+            }
+        }
+    };
+    let headers = res.headers_mut();
+    headers.insert("Cache-Control", format!("public, max-age={max_age}").parse().unwrap());
+    headers.insert("ETag", etag.parse().unwrap());
+    headers.insert("Last-Modified", http_date(modified).parse().unwrap());
+    Ok((state, res))
+}
+
+fn error_response(state: State, (status, e): (StatusCode, String)) -> (State, Response<Body>) {
+    match status.is_server_error() {
+        true => error!("{e}"),
+        false => debug!("{e}"),
+    }
+    let res = create_response(&state, status, mime::TEXT_PLAIN, status.canonical_reason().unwrap_or_default());
+    (state, res)
+}
+
+// Split /thumbnails/<width>/<site path>.<format> into its parts, only allowed widths are accepted
+fn parse_variant_path(uri_path: &str) -> Option<(String, Transform)> {
+    let (width, variant) = uri_path.strip_prefix(THUMBNAILS_ROUTE)?.split_once('/')?;
+    let width = width.parse::<u32>().ok().filter(|width| VARIANT_WIDTHS.contains(width))?;
+    let (site_path, extension) = variant.rsplit_once('.')?;
+    let format = VariantFormat::from_extension(extension).filter(|format| VariantFormat::PICTURE_SOURCES.contains(format))?;
+    Some((decode_path(site_path)?, Transform::thumbnail(width, format)))
+}
+
+// Validate the query of /img against the allowlist, the format defaults to the one of the picture
+fn parse_image_query(site_path: &str, query: &str, allowed_sizes: &[u32]) -> Result<Transform, (StatusCode, String)> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, format!("{e} for /img/{site_path}"));
+    let query = serde_urlencoded::from_str::<ImageQuery>(query).map_err(|e| bad_request(format!("invalid query: {e}")))?;
+    for size in [query.w, query.h].into_iter().flatten() {
+        if !allowed_sizes.contains(&size) {
+            return Err(bad_request(format!("size {size} is not allowed, expected one of {allowed_sizes:?}")))
+        }
+    }
+    let fit = match &query.fit {
+        Some(fit) => Fit::from_name(fit).ok_or_else(|| bad_request(format!("unknown fit '{fit}'")))?,
+        None => Fit::Contain,
+    };
+    let format = match &query.format {
+        Some(format) => VariantFormat::from_extension(format).ok_or_else(|| bad_request(format!("unknown format '{format}'")))?,
+        None => Path::new(site_path).extension()
+            .and_then(|extension| VariantFormat::from_extension(&extension.to_string_lossy()))
+            .ok_or_else(|| bad_request("cannot keep the format of the picture, give a format".to_string()))?,
+    };
+    Ok(Transform { width: query.w, height: query.h, fit, format })
+}
+
+// Location of a file of the website, refusing anything that could escape the website folder
+fn content_path(site_path: &str) -> Option<PathBuf> {
+    let path = Path::new(site_path);
+    match !site_path.is_empty() && path.components().all(|component| matches!(component, Component::Normal(_))) {
+        true => Some(Path::new(CONTENT_ROOT).join(path)),
+        false => None,
+    }
+}
+
+// Pictures of the members pages are only served to members, and those of the unpublished
+// articles to editors, like the pages themselves
+fn is_reserved(site_path: &str, member: bool, editor: bool) -> bool {
+    (!member && access::is_members_only(Path::new(site_path))) || (!editor && is_unpublished(site_path))
+}
+
+// Location and modification time of a picture that can be decoded
+fn source_file(site_path: &str) -> Result<(PathBuf, SystemTime), (StatusCode, String)> {
+    let not_found = |e: String| (StatusCode::NOT_FOUND, e);
+    let source = content_path(site_path).ok_or_else(|| not_found(format!("invalid picture path {site_path}")))?;
+    if !ImageFormat::from_path(&source).is_ok_and(|format| format.reading_enabled()) {
+        return Err(not_found(format!("{} is not a supported picture", source.display())))
+    }
+    let modified = fs::metadata(&source).and_then(|metadata| metadata.modified())
+        .map_err(|e| not_found(format!("{e}: {}", source.display())))?;
+    Ok((source, modified))
+}
+
+// Changes whenever the picture or the transform changes, so it can be checked without generating the variant
+fn variant_etag(site_path: &str, transform: &Transform, modified: SystemTime) -> String {
+    let mut hasher = DefaultHasher::new();
+    site_path.hash(&mut hasher);
+    transform.cache_key().hash(&mut hasher);
+    transform.format.extension().hash(&mut hasher);
+    modified.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+// If-None-Match takes precedence over If-Modified-Since, as in RFC 7232
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = headers.get("If-None-Match").and_then(|value| value.to_str().ok()) {
+        return if_none_match.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag || tag == "*")
+    }
+    let if_modified_since = headers.get("If-Modified-Since")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+    match if_modified_since {
+        // HTTP dates have a one second precision
+        Some(since) => DateTime::<Utc>::from(modified).timestamp() <= since.timestamp(),
+        None => false,
+    }
+}
+
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// Forget the cached variants of every picture under a folder of the website, eg news/talks/<article>
+pub fn invalidate_variants(cache_dir: &str, site_folder: &str) {
+    for key in fs::read_dir(cache_dir).into_iter().flatten().flatten() {
+        let cached = key.path().join(site_folder);
+        if let Err(e) = fs::remove_dir_all(&cached) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("cannot remove the cached variants {}: {e}", cached.display());
+            }
+        }
+    }
+}
+
+// Read a variant from the cache, (re)generating it when missing or older than its picture
+fn cached_variant(cache_dir: &str, site_path: &str, transform: Transform) -> Result<Vec<u8>, (StatusCode, String)> {
+    let (source, source_modified) = source_file(site_path)?;
+
+    let cached = Path::new(cache_dir).join(transform.cache_key()).join(format!("{site_path}.{}", transform.format.extension()));
+    let is_fresh = fs::metadata(&cached).and_then(|metadata| metadata.modified())
+        .map(|cached_modified| cached_modified >= source_modified)
+        .unwrap_or(false);
+    if is_fresh {
+        if let Ok(variant) = fs::read(&cached) {
+            return Ok(variant)
+        }
+    }
+
+    let internal_error = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}: {}", source.display()));
+    let picture = image::open(&source).map_err(|e| internal_error(e.to_string()))?;
+    let variant = encode(&transform.apply(picture), transform.format).map_err(internal_error)?;
+    store(&cached, &variant).map_err(|e| internal_error(format!("cannot cache variant: {e}")))?;
+    Ok(variant)
+}
+
+fn encode(picture: &DynamicImage, format: VariantFormat) -> Result<Vec<u8>, String> {
+    match format {
+        VariantFormat::WebP => {
+            let rgba = picture.to_rgba8();
+            Ok(webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode(WEBP_QUALITY).to_vec())
+        }
+        VariantFormat::Avif => {
+            let mut variant = Vec::new();
+            picture.write_with_encoder(AvifEncoder::new_with_speed_quality(&mut variant, AVIF_SPEED, AVIF_QUALITY))
+                .map_err(|e| e.to_string())?;
+            Ok(variant)
+        }
+        VariantFormat::Jpeg => {
+            let mut variant = Vec::new();
+            picture.to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(&mut variant, JPEG_QUALITY))
+                .map_err(|e| e.to_string())?;
+            Ok(variant)
+        }
+        VariantFormat::Png => {
+            let mut variant = Cursor::new(Vec::new());
+            picture.write_to(&mut variant, ImageFormat::Png).map_err(|e| e.to_string())?;
+            Ok(variant.into_inner())
+        }
+    }
+}
+
+// Write to a temporary file first, so that concurrent requests never read a partial variant
+fn store(cached: &Path, variant: &[u8]) -> std::io::Result<()> {
+    static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+    if let Some(folder) = cached.parent() {
+        fs::create_dir_all(folder)?;
+    }
+    let temporary = cached.with_extension(format!("{}.tmp", TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&temporary, variant)?;
+    fs::rename(&temporary, cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserves_the_pictures_of_the_members_pages() {
+        let (site_path, _) = parse_variant_path("/thumbnails/400/_pages/hidden/2025-27-03-konstantin-bottinger/image.webp").unwrap();
+        assert!(is_reserved(&site_path, false, true));
+        assert!(!is_reserved(&site_path, true, true));
+        // /img/<site path> and its variations resolving to the same file
+        assert!(is_reserved("_pages/hidden/2025-27-03-konstantin-bottinger/image.webp", false, true));
+        assert!(is_reserved("_pages/./hidden/2025-27-03-konstantin-bottinger/image.webp", false, true));
+        assert!(is_reserved("_pages//hidden/2025-27-03-konstantin-bottinger/image.webp", false, true));
+        assert!(!is_reserved("assets/img/bayen.webp", false, true));
+        assert!(content_path("./_pages/hidden/2025-27-03-konstantin-bottinger/image.webp").is_none());
+        assert!(is_reserved(&decode_path("_pages/%68idden/image.webp").unwrap(), false, true));
+    }
+
+    // Removes the draft article written by a test
+    struct DraftFolder(PathBuf);
+
+    impl Drop for DraftFolder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn reserves_the_pictures_of_the_unpublished_articles() {
+        let folder = format!("test-draft-{}", std::process::id());
+        let draft = DraftFolder(Path::new("orailix.com/news/talks").join(&folder));
+        fs::create_dir_all(&draft.0).unwrap();
+        fs::write(draft.0.join("manifest.txt"), "title=Draft\ndate=2024-14-08\ndraft=true\n").unwrap();
+
+        let (site_path, _) = parse_variant_path(&format!("/thumbnails/400/news/talks/{folder}/image.png.webp")).unwrap();
+        assert!(is_reserved(&site_path, true, false));
+        assert!(!is_reserved(&site_path, true, true));
+        assert!(is_reserved(&format!("news/talks/{folder}/image.png"), false, false));
+        assert!(is_reserved(&format!("news/talks/./{folder}/image.png"), true, false));
+        assert!(!is_reserved("news/talks/2023-16-11-eu-parliament/image.webp", false, false));
+
+        fs::write(draft.0.join("manifest.txt"), "title=Draft\ndate=2024-14-08\n").unwrap();
+        assert!(!is_reserved(&site_path, false, false));
+    }
+
+    #[test]
+    fn encodes_the_paths_of_the_variants() {
+        let url = variant_url("news/talks/2024 talk/slides, page 1.png", 400, VariantFormat::WebP);
+        assert_eq!(url, "/thumbnails/400/news/talks/2024%20talk/slides%2C%20page%201.png.webp");
+        let (site_path, _) = parse_variant_path(&url).unwrap();
+        assert_eq!(site_path, "news/talks/2024 talk/slides, page 1.png");
+        assert!(parse_variant_path("/thumbnails/400/news/%2.png.webp").is_none());
+        assert!(parse_variant_path("/thumbnails/400/news/%ff.png.webp").is_none());
+        assert!(content_path(&decode_path("news/%2e%2e/users.json").unwrap()).is_none());
+    }
+}
@@ -0,0 +1,401 @@
+use std::fmt;
+use std::io::IsTerminal;
+use std::panic::RefUnwindSafe;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use futures_util::FutureExt;
+use gotham::anyhow;
+use gotham::handler::{Handler, HandlerFuture, NewHandler};
+use gotham::hyper::body::HttpBody;
+use gotham::hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, REFERER, USER_AGENT};
+use gotham::hyper::{Body, HeaderMap, Method, Response, StatusCode, Uri, Version};
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{client_addr, FromState, State};
+use gotham_derive::StateData;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tracing::field::{Field, Visit};
+use tracing::{debug, info_span, Event, Instrument, Span, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{filter_fn, EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+use crate::config::{LogFormat, LogOutput, LogRotation, LoggingConfig};
+use crate::session_management::current_login;
+
+// Target of the access log events, told apart from the other messages
+const ACCESS_TARGET: &str = "access";
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+// Longest request ID accepted from a client or proxy
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// Logs of the server, from the [logging] section of the configuration. The returned guard writes
+// the last lines when dropped, it must be kept until the server stops
+pub fn init(config: &LoggingConfig) -> Result<WorkerGuard, String> {
+    let level = config.level.parse::<LevelFilter>().map_err(|_| format!("invalid level '{}', expected error, warn, info, debug or trace", config.level))?;
+    let (writer, guard) = match config.output {
+        LogOutput::Stdout => tracing_appender::non_blocking(std::io::stdout()),
+        LogOutput::File => {
+            let rotation = match config.rotation {
+                LogRotation::Minutely => Rotation::MINUTELY,
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+            std::fs::create_dir_all(&config.directory).map_err(|e| format!("cannot create {}: {e}", config.directory))?;
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix("orailix")
+                .filename_suffix("log")
+                .max_log_files(config.max_files.max(1))
+                .build(&config.directory)
+                .map_err(|e| format!("cannot write the logs in {}: {e}", config.directory))?;
+            tracing_appender::non_blocking(appender)
+        }
+    };
+    // RUST_LOG takes over, to also see the messages of the dependencies
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(format!("warn,orailix_backend={level}")));
+    let messages = filter_fn(|metadata| metadata.target() != ACCESS_TARGET);
+    let access_log = config.access_log;
+    let access = filter_fn(move |metadata| access_log && metadata.target() == ACCESS_TARGET);
+    let (message_layer, access_layer): (BoxedLayer, BoxedLayer) = match config.format {
+        LogFormat::Combined => (
+            tracing_subscriber::fmt::layer().with_writer(writer.clone()).with_ansi(config.output == LogOutput::Stdout && std::io::stdout().is_terminal()).with_filter(filter).with_filter(messages).boxed(),
+            tracing_subscriber::fmt::layer().with_writer(writer).event_format(AccessLine).with_filter(access).boxed(),
+        ),
+        LogFormat::Json => (
+            tracing_subscriber::fmt::layer().json().flatten_event(true).with_writer(writer.clone()).with_filter(filter).with_filter(messages).boxed(),
+            tracing_subscriber::fmt::layer().json().flatten_event(true).with_current_span(false).with_span_list(false).with_writer(writer).with_filter(access).boxed(),
+        ),
+    };
+    tracing_subscriber::registry().with(vec![message_layer, access_layer]).try_init().map_err(|e| e.to_string())?;
+    Ok(guard)
+}
+
+// Messages of the command line subcommands, on stderr so that they do not mix with their output
+pub fn init_command_line() {
+    let _ = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(LevelFilter::INFO)
+        .without_time()
+        .with_target(false)
+        .try_init();
+}
+
+// Access log lines in the Combined Log Format are written as they are, without time nor level
+struct AccessLine;
+
+impl<S, N> FormatEvent<S, N> for AccessLine
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        writeln!(writer, "{}", message.0)
+    }
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+// Login of the request, noted for the access log before the session middleware takes the session back
+#[derive(StateData)]
+struct RequestUser(String);
+
+// Notes the user of every request, first in the pipeline to also see the requests refused by the others
+#[derive(Clone)]
+pub struct RequestUserMiddleware;
+
+impl NewMiddleware for RequestUserMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl Middleware for RequestUserMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        // After the handler, which may log the user in or out
+        let note = |mut state: State| {
+            if let Some(user_id) = current_login(&state).map(|login_data| login_data.user_id.clone()) {
+                state.put(RequestUser(user_id));
+            }
+            state
+        };
+        chain(state)
+            .map(move |result| match result {
+                Ok((state, res)) => Ok((note(state), res)),
+                Err((state, e)) => Err((note(state), e)),
+            })
+            .boxed()
+    }
+}
+
+// One request as logged
+struct AccessEntry {
+    ip: Option<String>,
+    user: Option<String>,
+    time: DateTime<Utc>,
+    method: Method,
+    target: String,
+    version: Version,
+    status: u16,
+    bytes: Option<u64>,
+    referrer: Option<String>,
+    user_agent: Option<String>,
+    request_id: String,
+    duration: Duration,
+}
+
+impl AccessEntry {
+    // What is known before handling the request
+    fn of_request(state: &State, request_id: &str) -> Self {
+        let headers = HeaderMap::borrow_from(state);
+        let header = |name: HeaderName| headers.get(name).map(|value| String::from_utf8_lossy(value.as_bytes()).to_string());
+        Self {
+            ip: client_addr(state).map(|address| address.ip().to_string()),
+            user: None,
+            time: Utc::now(),
+            method: Method::borrow_from(state).clone(),
+            target: Uri::borrow_from(state).path_and_query().map(|target| target.to_string()).unwrap_or("/".to_string()),
+            version: Version::borrow_from(state).to_owned(),
+            status: 0,
+            bytes: None,
+            referrer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            request_id: request_id.to_string(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    fn respond(&mut self, state: &State, status: StatusCode, res: Option<&Response<Body>>, started: Instant) {
+        self.user = RequestUser::try_borrow_from(state).map(|RequestUser(user_id)| user_id.clone());
+        self.status = status.as_u16();
+        self.bytes = res.and_then(|res| {
+            let length = res.headers().get(CONTENT_LENGTH).and_then(|length| length.to_str().ok()?.parse().ok());
+            length.or(res.body().size_hint().exact())
+        });
+        self.duration = started.elapsed();
+    }
+
+    // host ident user [time] "request" status bytes "referrer" "user agent", followed by the request ID and the duration
+    fn combined(&self) -> String {
+        let field = |value: &Option<String>| value.clone().unwrap_or("-".to_string());
+        let quoted = |value: &Option<String>| format!("\"{}\"", value.as_deref().map(escape).unwrap_or("-".to_string()));
+        format!(
+            "{} - {} [{}] \"{} {} {:?}\" {} {} {} {} {} {}ms",
+            field(&self.ip),
+            field(&self.user),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            escape(&self.target),
+            self.version,
+            self.status,
+            self.bytes.map(|bytes| bytes.to_string()).unwrap_or("-".to_string()),
+            quoted(&self.referrer),
+            quoted(&self.user_agent),
+            self.request_id,
+            self.duration.as_millis(),
+        )
+    }
+
+    fn log(&self, format: LogFormat) {
+        match format {
+            LogFormat::Combined => tracing::info!(target: ACCESS_TARGET, "{}", self.combined()),
+            LogFormat::Json => tracing::info!(
+                target: ACCESS_TARGET,
+                ip = self.ip.as_deref(),
+                user = self.user.as_deref(),
+                method = %self.method,
+                path = %self.target,
+                protocol = ?self.version,
+                status = self.status,
+                bytes = self.bytes,
+                duration_ms = self.duration.as_millis() as u64,
+                referrer = self.referrer.as_deref(),
+                user_agent = self.user_agent.as_deref(),
+                request_id = %self.request_id,
+            ),
+        }
+    }
+}
+
+// Quotes and control characters would break the fields of the line
+pub(crate) fn escape(value: &str) -> String {
+    value.chars().flat_map(|c| match c {
+        '"' | '\\' => vec!['\\', c],
+        c if c.is_control() => format!("\\x{:02x}", c as u32).chars().collect(),
+        c => vec![c],
+    }).collect()
+}
+
+// ID of the request: the X-Request-Id of a proxy or client, so that it can be followed across servers,
+// or a new UUID v4 when missing or unfit for a log line
+fn request_id(state: &State) -> String {
+    let header = HeaderMap::borrow_from(state).get(REQUEST_ID_HEADER).and_then(|value| value.to_str().ok());
+    match header {
+        Some(id) if is_valid_request_id(id) => id.to_string(),
+        _ => generate_request_id(),
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.bytes().all(|c| c.is_ascii_alphanumeric() || b"-_.:".contains(&c))
+}
+
+fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = HEXLOWER.encode(&bytes);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// Runs a handler in its span, which the handler created and may have entered, with how long it took at the debug level
+pub fn timed(span: Span, future: Pin<Box<HandlerFuture>>) -> Pin<Box<HandlerFuture>> {
+    let started = Instant::now();
+    let handled = span.clone();
+    future
+        .instrument(span)
+        .map(move |result| {
+            let status = match &result {
+                Ok((_, res)) => res.status(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            debug!(parent: &handled, status = status.as_u16(), elapsed_ms = started.elapsed().as_millis() as u64, "handled");
+            result
+        })
+        .boxed()
+}
+
+// A handler giving an ID to the requests it answers and logging them. Around the headers middleware to log the responses as sent,
+// every message logged while handling a request is in its span, with its ID
+#[derive(Clone)]
+pub struct WithAccessLog<H> {
+    format: LogFormat,
+    handler: H,
+}
+
+impl<H> WithAccessLog<H> {
+    pub fn new(config: &LoggingConfig, handler: H) -> Self {
+        Self { format: config.format, handler }
+    }
+}
+
+impl<H> NewHandler for WithAccessLog<H>
+where
+    H: Handler + Clone + Send + Sync + RefUnwindSafe + 'static,
+{
+    type Instance = Self;
+
+    fn new_handler(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl<H> Handler for WithAccessLog<H>
+where
+    H: Handler + Send + 'static,
+{
+    fn handle(self, state: State) -> Pin<Box<HandlerFuture>> {
+        let started = Instant::now();
+        let request_id = request_id(&state);
+        let mut entry = AccessEntry::of_request(&state, &request_id);
+        let format = self.format;
+        let span = info_span!("request", id = %request_id);
+        let future = span.in_scope(|| self.handler.handle(state));
+        future
+            .instrument(span)
+            .map(move |result| {
+                let result = match result {
+                    Ok((state, mut res)) => {
+                        // Replaces the one of gotham, taken as is from the request
+                        if let Ok(value) = HeaderValue::from_str(&request_id) {
+                            res.headers_mut().insert(REQUEST_ID_HEADER, value);
+                        }
+                        entry.respond(&state, res.status(), Some(&res), started);
+                        Ok((state, res))
+                    }
+                    Err((state, e)) => {
+                        entry.respond(&state, StatusCode::INTERNAL_SERVER_ERROR, None, started);
+                        Err((state, e))
+                    }
+                };
+                entry.log(format);
+                result
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    #[test]
+    fn writes_the_combined_log_format() {
+        let mut entry = AccessEntry {
+            ip: Some("127.0.0.1".to_string()),
+            user: Some("alice".to_string()),
+            time: Utc.with_ymd_and_hms(2026, 10, 19, 8, 5, 3).unwrap(),
+            method: Method::GET,
+            target: "/news/?q=\"a b\"".to_string(),
+            version: Version::HTTP_11,
+            status: 200,
+            bytes: Some(5120),
+            referrer: Some("https://orailix.com/".to_string()),
+            user_agent: Some("curl/8.0".to_string()),
+            request_id: "5c9d8a0e-1d2c-4c59-9d7e-3f4f2b8b0c11".to_string(),
+            duration: Duration::from_millis(12),
+        };
+        assert_eq!(
+            entry.combined(),
+            r#"127.0.0.1 - alice [19/Oct/2026:08:05:03 +0000] "GET /news/?q=\"a b\" HTTP/1.1" 200 5120 "https://orailix.com/" "curl/8.0" 5c9d8a0e-1d2c-4c59-9d7e-3f4f2b8b0c11 12ms"#
+        );
+
+        entry.user = None;
+        entry.bytes = None;
+        entry.referrer = None;
+        entry.user_agent = Some("evil\"\nagent".to_string());
+        assert!(entry.combined().contains(r#"127.0.0.1 - - [19/Oct/2026"#));
+        assert!(entry.combined().contains(r#" 200 - "-" "evil\"\x0aagent" "#));
+    }
+
+    #[test]
+    fn accepts_request_ids_fit_for_a_log_line() {
+        assert!(is_valid_request_id("5c9d8a0e-1d2c-4c59-9d7e-3f4f2b8b0c11"));
+        assert!(is_valid_request_id("lb:01HF3K9Z_7.2"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("a b"));
+        assert!(!is_valid_request_id("id\"\n127.0.0.1 - admin"));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+        let generated = generate_request_id();
+        assert!(is_valid_request_id(&generated));
+        assert_eq!((generated.len(), &generated[14..15]), (36, "4"));
+        assert_ne!(generated, generate_request_id());
+    }
+}
@@ -1,16 +1,51 @@
 mod session_management;
 mod articles;  // Declares the articles module
+mod markdown;
+mod config;
+mod metadata;
+mod validate;
+mod scaffold;
+mod images;
+mod users;
+mod session_backend;
+mod access;
+mod csrf;
+mod throttle;
+mod audit;
+mod totp;
+mod api_tokens;
+mod admin_articles;
+mod uploads;
+mod content_git;
+mod headers;
+mod csp;
+mod cors;
+mod logging;
 use crate::articles::get_articles_handler;
-
-use clap::{App, Arg};
+use crate::config::{Config, DEFAULT_CONFIG_PATH};
+use crate::session_backend::SessionBackend;
+use crate::access::RequireRole;
+use crate::config::SameSite;
+use crate::csrf::{csrf_token_handler, CsrfKey, CsrfMiddleware};
+use crate::throttle::LoginThrottle;
+use crate::headers::HeadersMiddleware;
+use crate::audit::{AuditEntry, AuditLog, AuditQuery};
+use crate::api_tokens::ApiTokenMiddleware;
+use crate::users::Role;
+use crate::logging::{RequestUserMiddleware, WithAccessLog};
+
+use clap::{App, AppSettings, Arg, SubCommand};
+use tracing::{debug, debug_span, error, info, warn};
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
 use gotham::pipeline::set::{new_pipeline_set, finalize_pipeline_set};
 use gotham::pipeline::new_pipeline;
 use gotham::middleware::state::StateMiddleware;
-use gotham::middleware::session::{NewSessionMiddleware};
+use gotham::middleware::session::NewSessionMiddleware;
 use gotham::router::builder::{build_router, DrawRoutes, DefineSingleRoute};
-use crate::session_management::{LoginData, OriginDomain, header_formatting};
+use crate::session_management::{connect_user, disconnect_user, is_user_connected, revoke_sessions_handler, LoginData, SessionExpiryMiddleware, SessionLimits, SessionStore, UserPath, SESSION_STATUS_ROUTE};
 use gotham::rustls;
 use gotham::rustls::NoClientAuth;
 use gotham::rustls::internal::pemfile::{certs, pkcs8_private_keys};
@@ -23,7 +58,6 @@ use gotham::helpers::http::response::create_response;
 use mime::{TEXT_HTML, IMAGE_JPEG, IMAGE_PNG, IMAGE_SVG, TEXT_CSS, TEXT_JAVASCRIPT, TEXT_XML, TEXT_PLAIN};
 use futures_util::{future, FutureExt};
 use std::fs;
-use serde::de::Unexpected::Str;
 
 fn get_main(mut state: State) -> Pin<Box<HandlerFuture>> {
 
@@ -35,14 +69,13 @@ fn get_main(mut state: State) -> Pin<Box<HandlerFuture>> {
             let uri = Uri::borrow_from(&state).to_string();
 
             // If we receive additional arguments in the URI we can handle them
-            let uri_elements = uri.split("&").collect::<Vec<&str>>();
+            let _uri_elements = uri.split("&").collect::<Vec<&str>>();
             //println!("Uri got {} arguments: {:?}", uri_elements.len(), uri_elements);
 
 
 
             let body_content = fs::read_to_string("orailix.com/index.html").unwrap();
-            let mut res = create_response(&state, StatusCode::OK, TEXT_HTML, body_content);
-            res = header_formatting(res, &state);
+            let res = create_response(&state, StatusCode::OK, TEXT_HTML, body_content);
             future::ok((state, res))
 
         }
@@ -68,12 +101,11 @@ fn _get_page(mut state: State) -> Pin<Box<HandlerFuture>> {
                 }
                 Err(e) => {
                     let body = fs::read_to_string("orailix.com/error_page.html").expect("Unable to read file");
-                    println!("get page link {} error {:?}", file_location, e);
+                    warn!("cannot read the page {file_location}: {e}");
                     (body, StatusCode::NOT_FOUND)
                 }
             };
-            let mut res = create_response(&state, response_content.1, TEXT_HTML, response_content.0);
-            res = header_formatting(res, &state);
+            let res = create_response(&state, response_content.1, TEXT_HTML, response_content.0);
             future::ok((state, res))
 
         }
@@ -83,26 +115,27 @@ fn _get_page(mut state: State) -> Pin<Box<HandlerFuture>> {
 }
 
 
+// Page of the missing files and unpublished articles
+const NOT_FOUND_PAGE: &str = "orailix.com/404.html";
 
 fn to_dir_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let span = debug_span!("to_dir_handler", path = Uri::borrow_from(&state).path());
     let f = body::to_bytes(Body::take_from(&mut state)).then(|full_body| match full_body {
         Ok(_valid_body) => {
-            let uri = Uri::borrow_from(&state).to_string();
-
-            // If we receive additional arguments in the URI we can handle them
-            let uri_elements = uri.split("&").collect::<Vec<&str>>();
-
-            let file_location = format!{"orailix.com{}", uri_elements[0]}.replace("[\"", "").replace("\"]", "");
-
-            let mut file_location = match file_location.contains('?') {
-                true => {file_location.split_once("?").unwrap().0.to_string()},
-                false => file_location
+            // Only members get the files of their folder, whichever route led here. The paths reaching a file
+            // by another way than the router sees it are refused, eg /./_pages/hidden/ out of the members scope
+            let site_path = access::site_path(Uri::borrow_from(&state).path()).map(str::to_string);
+            let refused = match &site_path {
+                Some(site_path) => access::is_members_only(Path::new(site_path)) && !access::is_granted(&state, Role::Member),
+                None => true,
             };
+            let mut site_path = site_path.unwrap_or_default();
 
             // If no extension in the path, it means we target a folder such as /members/, thus we provide the index.html present
-            if file_location.ends_with('/') {
-                file_location = format!("{file_location}index.html")
+            if site_path.is_empty() || site_path.ends_with('/') {
+                site_path = format!("{site_path}index.html")
             }
+            let file_location = format!("orailix.com/{site_path}");
 
             let file_extension = &file_location.split("/").collect::<Vec<&str>>();
             let file_extension = file_extension.last().unwrap().to_owned();
@@ -121,6 +154,8 @@ fn to_dir_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 "jpeg" => { IMAGE_JPEG}
                 "png" => {IMAGE_PNG}
                 "svg" => {IMAGE_SVG}
+                "gif" => {mime::IMAGE_GIF}
+                "webp" => {"image/webp".parse().unwrap()}
                 "html" => { TEXT_HTML}
                 "css" => { TEXT_CSS}
                 "js" => {TEXT_JAVASCRIPT}
@@ -133,51 +168,263 @@ fn to_dir_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
             };
 
 
-            let body_content = match File::open(&file_location) {
-                Ok(mut body) => {
+            // Unpublished articles and refused paths are served as missing pages
+            let unpublished = refused || articles::is_unpublished(&site_path);
+
+            // Markdown articles only ship an index.md, rendered on the fly within the article layout
+            let markdown_article = match file_location.strip_suffix("index.html") {
+                Some(article_folder) if !unpublished && !Path::new(&file_location).exists() => markdown::render_article(Path::new(article_folder)),
+                _ => None,
+            };
+
+            let file = match unpublished {
+                true => Err(std::io::ErrorKind::NotFound.into()),
+                false => File::open(&file_location),
+            };
+            let mut status = StatusCode::OK;
+            let mut mime_type = mime_type;
+            let body_content = match (markdown_article, file) {
+                (Some(page), _) => page.into_bytes(),
+                (None, Ok(mut body)) => {
                     let mut file_content = Vec::new();
                     match body.read_to_end(&mut file_content) {
                         Ok(_) => file_content,
                         _ => {
-                            println!("error reading at {}", file_location);
-                            format!("error reading").as_bytes().to_vec()
+                            error!("cannot read {file_location}");
+                            "error reading".as_bytes().to_vec()
                         }
                     }
                 }
-                Err(e) => {
-                    println!("error: {e:?}: {file_location}");
-                    let mut body = File::open("orailix.com/error_page.html").expect("Unable to read file");
-                    //println!("error get support {:?} for {}", e, file_location);
-                    let mut file_content = Vec::new();
-                    body.read_to_end(&mut file_content).expect("Unable to read");
-                    file_content
+                (None, Err(e)) => {
+                    debug!("{e}: {file_location}");
+                    status = StatusCode::NOT_FOUND;
+                    mime_type = TEXT_HTML;
+                    fs::read(NOT_FOUND_PAGE).unwrap_or_else(|_| b"Not Found".to_vec())
                 }
             };
-            let mut res = create_response(&state, StatusCode::OK, mime_type, body_content);
-            res = header_formatting(res, &state);
+            // Articles get their link preview metadata from the manifest
+            let body_content = match mime_type == TEXT_HTML {
+                true => metadata::inject_article_metadata(&file_location, body_content, &Config::borrow_from(&state).site.public_origin),
+                false => body_content,
+            };
+            let res = create_response(&state, status, mime_type, body_content);
             future::ok((state, res))
 
         }
         Err(e) => future::err((state, e.into())),
     });
-    f.boxed()
+    logging::timed(span, f.boxed())
 }
 
 
 
 pub fn main() {
     let cmd: clap::ArgMatches = parse_cmd();
+    if cmd.subcommand_name().is_some() {
+        logging::init_command_line();
+    }
+
+    // Content management subcommands run instead of the server
+    match cmd.subcommand() {
+        ("new-article", Some(sub_cmd)) => {
+            let article = scaffold::NewArticle {
+                category: sub_cmd.value_of("category").unwrap_or_default().to_string(),
+                title: sub_cmd.value_of("title").unwrap_or_default().to_string(),
+                date: sub_cmd.value_of("date").map(str::to_string),
+                slug: sub_cmd.value_of("slug").map(str::to_string),
+                url: sub_cmd.value_of("url").map(str::to_string),
+                picture: sub_cmd.value_of("picture").map(str::to_string),
+                html: sub_cmd.is_present("html"),
+            };
+            match scaffold::new_article(&article) {
+                Ok(article_path) => println!("Article created in {article_path}"),
+                Err(e) => {
+                    println!("error: {e}");
+                    std::process::exit(1)
+                }
+            }
+            return
+        }
+        ("validate", Some(_)) => {
+            if !validate::validate_news() {
+                std::process::exit(1)
+            }
+            return
+        }
+        ("hash-password", Some(_)) => {
+            match read_password().and_then(|password| users::hash_password(&password)) {
+                Ok(hash) => println!("{hash}"),
+                Err(e) => {
+                    println!("error: {e}");
+                    std::process::exit(1)
+                }
+            }
+            return
+        }
+        ("audit", Some(sub_cmd)) => {
+            let config = Config::load(cmd.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH));
+            let (query, args) = match sub_cmd.subcommand() {
+                ("tail", Some(args)) => (AuditQuery { limit: args.value_of("lines").and_then(|lines| lines.parse().ok()), ..AuditQuery::default() }, args),
+                ("query", Some(args)) => {
+                    let value = |name: &str| args.value_of(name).map(str::to_string);
+                    let query = AuditQuery {
+                        event: value("event"),
+                        actor: value("actor"),
+                        ip: value("ip"),
+                        target: value("target"),
+                        since: value("since"),
+                        until: value("until"),
+                        limit: args.value_of("limit").and_then(|limit| limit.parse().ok()),
+                    };
+                    (query, args)
+                }
+                _ => {
+                    println!("error: {}", sub_cmd.usage());
+                    std::process::exit(1)
+                }
+            };
+            match AuditLog::new(&config.audit.file).query(&query) {
+                Ok(entries) => for entry in entries {
+                    match args.is_present("json") {
+                        true => println!("{}", serde_json::to_string(&entry).unwrap_or_default()),
+                        false => println!("{}", entry.to_line()),
+                    }
+                },
+                Err(e) => {
+                    println!("error: {e}");
+                    std::process::exit(1)
+                }
+            }
+            return
+        }
+        ("token", Some(sub_cmd)) => {
+            let config = Config::load(cmd.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH));
+            let tokens_file = &config.auth.tokens_file;
+            let result = match sub_cmd.subcommand() {
+                ("create", Some(args)) => {
+                    let username = args.value_of("username").unwrap_or_default();
+                    let scopes = args.values_of("scope").map(|scopes| scopes.filter_map(users::Role::from_name).collect()).unwrap_or_default();
+                    api_tokens::create_token(tokens_file, &config.auth.users_file, username, args.value_of("name").unwrap_or_default(), scopes)
+                        .map(|token| format!("{token}\nSend it as Authorization: Bearer <token>, it is not shown again"))
+                }
+                ("revoke", Some(args)) => {
+                    let id = args.value_of("id").unwrap_or_default();
+                    api_tokens::revoke_token(tokens_file, id).map(|_| format!("Token {id} revoked"))
+                }
+                ("list", Some(_)) => api_tokens::list_tokens(tokens_file).map(|tokens| tokens.join("\n")),
+                _ => Err(sub_cmd.usage().to_string()),
+            };
+            match result {
+                Ok(message) => println!("{message}"),
+                Err(e) => {
+                    println!("error: {e}");
+                    std::process::exit(1)
+                }
+            }
+            return
+        }
+        ("user", Some(sub_cmd)) => {
+            let config = Config::load(cmd.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH));
+            let users_file = config.auth.users_file.clone();
+            let result = match sub_cmd.subcommand() {
+                ("add", Some(args)) => {
+                    let username = args.value_of("username").unwrap_or_default();
+                    let roles = args.values_of("role").map(|roles| roles.filter_map(users::Role::from_name).collect()).unwrap_or_default();
+                    read_password()
+                        .and_then(|password| users::add_user(&users_file, username, args.value_of("display-name").unwrap_or_default(), roles, &password))
+                        .map(|_| format!("User {username} added to {users_file}"))
+                }
+                ("passwd", Some(args)) => {
+                    let username = args.value_of("username").unwrap_or_default();
+                    read_password()
+                        .and_then(|password| users::set_password(&users_file, username, &password))
+                        .and_then(|_| revoke_stored_sessions(&config, username))
+                        .map(|revoked| format!("Password of {username} changed, {revoked} sessions revoked"))
+                }
+                ("disable", Some(args)) => {
+                    let username = args.value_of("username").unwrap_or_default();
+                    users::disable_user(&users_file, username)
+                        .and_then(|_| revoke_stored_sessions(&config, username))
+                        .map(|revoked| format!("User {username} disabled, {revoked} sessions revoked"))
+                }
+                ("totp", Some(args)) if args.is_present("disable") => {
+                    let username = args.value_of("username").unwrap_or_default();
+                    users::disable_totp(&users_file, username)
+                        .and_then(|_| revoke_stored_sessions(&config, username))
+                        .map(|revoked| format!("Second factor of {username} removed, {revoked} sessions revoked"))
+                }
+                ("totp", Some(args)) => {
+                    let username = args.value_of("username").unwrap_or_default();
+                    users::enable_totp(&users_file, username, &config.auth.totp_issuer)
+                        .and_then(|(uri, recovery_codes)| {
+                            let revoked = revoke_stored_sessions(&config, username)?;
+                            let qr_code = totp::qr_code(&uri)?;
+                            Ok(format!(
+                                "Scan this QR code with an authenticator app, or enter the URI:\n{qr_code}\n{uri}\n\n\
+                                Recovery codes, each can replace a TOTP code once. Keep them safe, they are not shown again:\n{}\n\n\
+                                Second factor of {username} enrolled, {revoked} sessions revoked",
+                                recovery_codes.join("\n")
+                            ))
+                        })
+                }
+                ("list", Some(_)) => users::list_users(&users_file).map(|users| users.join("\n")),
+                _ => Err(sub_cmd.usage().to_string()),
+            };
+            match result {
+                Ok(message) => println!("{message}"),
+                Err(e) => {
+                    println!("error: {e}");
+                    std::process::exit(1)
+                }
+            }
+            return
+        }
+        _ => {}
+    }
+
     let addr: String = cmd.value_of("ip").unwrap_or_default().to_string();
-    let origin: String = cmd.value_of("origin").unwrap_or_default().to_string();
-    println!("The origin of the website is: {}", origin);
+    let origins: Vec<String> = cmd.values_of("origin").map(|origins| origins.map(str::to_string).collect()).unwrap_or_default();
+    let config = Config::load(cmd.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH));
+    // Kept until the server stops, so that the last lines are written
+    let log_guard = match logging::init(&config.logging) {
+        Ok(log_guard) => log_guard,
+        Err(e) => {
+            println!("error: invalid [logging] configuration: {e}");
+            std::process::exit(1)
+        }
+    };
+    debug!("{cmd:?}");
+    if config.images.pregenerate {
+        images::pregenerate_article_variants(config.images.cache_dir.clone());
+    }
 
+    // The backend is shared with the login handler, which replaces the session of the users logging in.
+    // It must keep the sessions at least as long as their lifetime, the expiry is enforced by SessionExpiryMiddleware
+    let session_limits = SessionLimits {
+        session_lifetime: config.auth.session_lifetime,
+        idle_timeout: config.auth.idle_timeout,
+    };
+    let session_backend = match SessionBackend::from_config(&config.sessions, Duration::from_secs(session_limits.session_lifetime.max(0) as u64)) {
+        Ok(session_backend) => session_backend,
+        Err(e) => {
+            error!("invalid [sessions] configuration: {e}");
+            drop(log_guard);
+            std::process::exit(1)
+        }
+    };
+    session_backend.collect_garbage_every(Duration::from_secs(config.sessions.gc_interval));
+    let session_store = SessionStore::new(session_backend.clone(), cmd.is_present("https"), config.sessions.same_site);
+    let session_middleware = match config.sessions.same_site {
+        SameSite::Lax => NewSessionMiddleware::new(session_backend),
+        SameSite::Strict => NewSessionMiddleware::new(session_backend).with_strict_same_site_enforcement(),
+    };
     let middleware = match cmd.is_present("https") {
         true => {
             // If Https is enabled, create a secure middleware handling LoginData over sessions
-            NewSessionMiddleware::default().with_session_type::<Option<LoginData>>()
+            session_middleware.with_session_type::<Option<LoginData>>()
         }
         false => {
-            NewSessionMiddleware::default()
+            session_middleware
                 // Configure the type of data which we want to store in the session.
                 // See the custom_data_type example for storing more complex data.
                 .with_session_type::<Option<LoginData>>()
@@ -197,19 +444,62 @@ pub fn main() {
             .build(),
     );
 
-    // Creating a pipeline to combine two middleware:
-    //  1) Origin tracker for customizing the headers
-    //  2) Session Management for login data and more (eg accessing the blog utilities)
-    let origin_domain = OriginDomain::new(origin);
+    let csrf_key = match CsrfKey::load(&config.csrf.key_file) {
+        Ok(csrf_key) => csrf_key,
+        Err(e) => {
+            error!("invalid [csrf] configuration: {e}");
+            drop(log_guard);
+            std::process::exit(1)
+        }
+    };
+    let headers_middleware = match HeadersMiddleware::new(&config.headers, &config.csp, &config.cors, &origins, cmd.is_present("https")) {
+        Ok(headers_middleware) => headers_middleware,
+        Err(e) => {
+            error!("invalid [headers], [csp] or [cors] configuration: {e}");
+            drop(log_guard);
+            std::process::exit(1)
+        }
+    };
+    let login_throttle = LoginThrottle::new(config.login.clone());
+    let audit_log = AuditLog::new(&config.audit.file);
+    let api_tokens = ApiTokenMiddleware::new(&config.auth.tokens_file, &config.auth.users_file);
+
+    let logging_config = config.logging.clone();
+
+    // Creating a pipeline to combine nine middleware:
+    //  1) User of the request, noted for the access log
+    //  2) Configuration loaded at startup
+    //  3) Session store to rotate the session on login
+    //  4) Failed logins per IP address and username, slowing down password guessing
+    //  5) Audit log of the security events
+    //  6) Session expiry, logging out old and idle sessions
+    //  7) API tokens, logging in the automation clients for a single request
+    //  8) CSRF tokens, required by every request changing something, except with an API token
+    //  9) Session Management for login data and more (eg accessing the blog utilities)
     let (pipelines, extended) = pipelines.add(
         new_pipeline()
-            .add(StateMiddleware::new(origin_domain))
+            .add(RequestUserMiddleware)
+            .add(StateMiddleware::new(config))
+            .add(StateMiddleware::new(session_store))
+            .add(StateMiddleware::new(login_throttle))
+            .add(StateMiddleware::new(audit_log))
+            .add(SessionExpiryMiddleware::new(session_limits))
+            .add(api_tokens)
+            .add(CsrfMiddleware::new(csrf_key))
             .build(),
     );
 
+    // Route guards restricting scopes of the router to logged in users with a given role
+    let (pipelines, members_only) = pipelines.add(new_pipeline().add(RequireRole::new(Role::Member)).build());
+    let (pipelines, editors_only) = pipelines.add(new_pipeline().add(RequireRole::new(Role::Editor)).build());
+    let (pipelines, admins_only) = pipelines.add(new_pipeline().add(RequireRole::new(Role::Admin)).build());
+
     let pipeline_set = finalize_pipeline_set(pipelines);
     let default_chain = (default, ());
     let extended_chain = (extended, default_chain);
+    let members_chain = (members_only, extended_chain);
+    let editors_chain = (editors_only, extended_chain);
+    let admins_chain = (admins_only, extended_chain);
 
 
     let router = build_router(extended_chain, pipeline_set, |route| {
@@ -222,15 +512,55 @@ pub fn main() {
             route.get("").to(get_articles_handler);
         });
 
+        route.post("/api/login").to(connect_user);
+        route.post("/api/logout").to(disconnect_user);
+        route.get("/api/csrf-token").to(csrf_token_handler);
+        route.get(SESSION_STATUS_ROUTE).to(is_user_connected);
+        route.post(csp::REPORT_ROUTE).to(csp::csp_report_handler);
+
         route.scope("/", |route| {
             route.get("").to(get_main);
         });
+
+        // Internal pages, reserved to members
+        route.with_pipeline_chain(members_chain, |route| {
+            route.get("/_pages/hidden/*").to(to_dir_handler);
+        });
+
+        // Publishing, reserved to editors
+        route.with_pipeline_chain(editors_chain, |route| {
+            route.post("/api/admin/articles/:category").to(admin_articles::create_article_handler);
+            route.put("/api/admin/articles/:category/:folder").to(admin_articles::update_article_handler);
+            route.post("/api/admin/articles/:category/:folder/publish").to(admin_articles::publish_article_handler);
+            route.post("/api/admin/articles/:category/:folder/unpublish").to(admin_articles::unpublish_article_handler);
+            route.delete("/api/admin/articles/:category/:folder").to(admin_articles::delete_article_handler);
+            route.post("/api/admin/articles/:category/:folder/media").to(uploads::upload_handler);
+            route.post(uploads::MEDIA_ROUTE).to(uploads::upload_handler);
+            route.get("/api/admin/articles/:category/:folder/history").to(admin_articles::article_history_handler);
+        });
+
+        route.with_pipeline_chain(admins_chain, |route| {
+            route.delete("/api/admin/users/:username/sessions").with_path_extractor::<UserPath>().to(revoke_sessions_handler);
+            route.get("/api/admin/audit").to(audit::audit_handler);
+        });
+
+        // Resized variants of the article pictures
+        route.get("/thumbnails/*").to(images::thumbnail_handler);
+        // Any picture of the website, resized and transcoded on demand
+        route.get("/img/*").to(images::image_handler);
         
         route.get("/*").to(to_dir_handler);
 
 
     });
 
+    // Security and CORS headers of every response, those of the router included (eg 404)
+    let router = headers_middleware.around(router);
+    // One access log line per request, as answered
+    let router = WithAccessLog::new(&logging_config, router);
+
+    info!("listening on {addr}");
+
     if cmd.is_present("https") {
         // TLS gotham server that load the .pem files
         gotham::start_with_tls(addr, router, build_config().unwrap())
@@ -243,6 +573,23 @@ pub fn main() {
 
 
 
+// The sessions kept on disk can be revoked without the server, the in-memory ones only through the admin API
+fn revoke_stored_sessions(config: &Config, username: &str) -> Result<usize, String> {
+    let ttl = Duration::from_secs(config.auth.session_lifetime.max(0) as u64);
+    let revoked = SessionStore::new(SessionBackend::from_config(&config.sessions, ttl)?, false, config.sessions.same_site).revoke_user_sessions(username)?;
+    let detail = format!("{revoked} sessions, from the command line");
+    AuditLog::new(&config.audit.file).record(AuditEntry::new("sessions_revoked", None, None, Some(username), detail));
+    Ok(revoked)
+}
+
+// Passwords are read on stdin so that they do not end up in the shell history
+fn read_password() -> Result<String, String> {
+    eprintln!("Password:");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password).map_err(|e| format!("cannot read the password: {e}"))?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
 // Load the certificates
 fn build_config() -> Result<rustls::ServerConfig, rustls::TLSError> {
     let mut cfg = rustls::ServerConfig::new(NoClientAuth::new());
@@ -259,7 +606,9 @@ fn build_config() -> Result<rustls::ServerConfig, rustls::TLSError> {
 }
 
 pub fn parse_cmd() -> clap::ArgMatches<'static> {
-    let matches = App::new("")
+    App::new("")
+        // Server arguments are not needed by the content management subcommands
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("ip")
             .short("ip")
             .long("ip")
@@ -274,13 +623,160 @@ pub fn parse_cmd() -> clap::ArgMatches<'static> {
         .arg(Arg::with_name("origin")
             .short("origin")
             .long("origin")
-            .help("Specifies Access-Control-Allow-Origin")
-            .required(true)
+            .value_name("ORIGIN")
+            .help("Also allow cross-origin requests from this origin (eg https://*.orailix.com), on top of cors.allowed_origins")
+            .multiple(true)
+            .number_of_values(1)
             .takes_value(true))
-        .get_matches();
-
-    println!("{:?}", matches);
-
-    return matches;
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .help("Configuration file (defaults to orailix.toml)")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("new-article")
+            .about("Create a new article folder with its manifest, a starter page and its preview picture")
+            .arg(Arg::with_name("category")
+                .long("category")
+                .help("News category, eg publications, talks or tech")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("title")
+                .long("title")
+                .help("Title of the article")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("date")
+                .long("date")
+                .value_name("YYYY-MM-DD")
+                .help("Date of the article (defaults to today)")
+                .takes_value(true))
+            .arg(Arg::with_name("slug")
+                .long("slug")
+                .help("Short name appended to the date in the folder name (defaults to the title)")
+                .takes_value(true))
+            .arg(Arg::with_name("url")
+                .long("url")
+                .help("External page of the article (eg a paper), no local page is created")
+                .takes_value(true))
+            .arg(Arg::with_name("picture")
+                .long("picture")
+                .value_name("FILE")
+                .help("Preview picture, copied in the article folder and downscaled if needed")
+                .takes_value(true))
+            .arg(Arg::with_name("html")
+                .long("html")
+                .help("Create an index.html and a manifest.txt instead of an index.md")))
+        .subcommand(SubCommand::with_name("validate")
+            .about("Check the manifests, pictures and pages of every article"))
+        .subcommand(SubCommand::with_name("hash-password")
+            .about("Read a password on stdin and print its Argon2id hash for the user store"))
+        .subcommand(SubCommand::with_name("audit")
+            .about("Read the audit log of logins and content changes")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("tail")
+                .about("Print the latest entries")
+                .arg(Arg::with_name("lines")
+                    .short("n")
+                    .help("Number of entries (defaults to 100)")
+                    .takes_value(true))
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Print the entries as JSON lines")))
+            .subcommand(SubCommand::with_name("query")
+                .about("Print the latest entries matching every given filter, a trailing * matches a prefix")
+                .arg(Arg::with_name("event")
+                    .long("event")
+                    .help("eg login_failed, or article_*")
+                    .takes_value(true))
+                .arg(Arg::with_name("actor")
+                    .long("actor")
+                    .help("Username who did it")
+                    .takes_value(true))
+                .arg(Arg::with_name("ip")
+                    .long("ip")
+                    .takes_value(true))
+                .arg(Arg::with_name("target")
+                    .long("target")
+                    .help("eg a username, or talks/* for the articles of a category")
+                    .takes_value(true))
+                .arg(Arg::with_name("since")
+                    .long("since")
+                    .help("First day (YYYY-MM-DD) or time (RFC 3339), included")
+                    .takes_value(true))
+                .arg(Arg::with_name("until")
+                    .long("until")
+                    .help("Last day (YYYY-MM-DD) or time (RFC 3339), included")
+                    .takes_value(true))
+                .arg(Arg::with_name("limit")
+                    .long("limit")
+                    .help("Number of entries, the latest are kept (defaults to 100)")
+                    .takes_value(true))
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Print the entries as JSON lines"))))
+        .subcommand(SubCommand::with_name("token")
+            .about("Manage the API tokens of the automation clients, sent as Authorization: Bearer")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("create")
+                .about("Create a token acting as a user and print it")
+                .arg(Arg::with_name("username")
+                    .help("User the token acts as")
+                    .required(true))
+                .arg(Arg::with_name("name")
+                    .long("name")
+                    .help("What the token is for, eg the CI job using it")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("scope")
+                    .long("scope")
+                    .help("Role the token can use, among those of the user, can be repeated (defaults to member)")
+                    .possible_values(&["admin", "editor", "member"])
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("revoke")
+                .about("Delete a token, the clients using it are refused at once")
+                .arg(Arg::with_name("id")
+                    .help("Identifier of the token, as shown by token list")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("list")
+                .about("List the tokens with their user, scopes and last use")))
+        .subcommand(SubCommand::with_name("user")
+            .about("Manage the accounts of the user store, passwords are read on stdin")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("add")
+                .about("Create an account")
+                .arg(Arg::with_name("username")
+                    .help("Login of the account")
+                    .required(true))
+                .arg(Arg::with_name("display-name")
+                    .long("display-name")
+                    .help("Name shown to the other users")
+                    .takes_value(true))
+                .arg(Arg::with_name("role")
+                    .long("role")
+                    .help("Role of the account, can be repeated (defaults to member)")
+                    .possible_values(&["admin", "editor", "member"])
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("passwd")
+                .about("Change the password of an account")
+                .arg(Arg::with_name("username")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("disable")
+                .about("Prevent an account from logging in")
+                .arg(Arg::with_name("username")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("totp")
+                .about("Enrol a TOTP second factor, printing its QR code and recovery codes")
+                .arg(Arg::with_name("username")
+                    .required(true))
+                .arg(Arg::with_name("disable")
+                    .long("disable")
+                    .help("Remove the second factor instead")))
+            .subcommand(SubCommand::with_name("list")
+                .about("List the accounts with their roles")))
+        .get_matches()
 }
 
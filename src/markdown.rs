@@ -0,0 +1,238 @@
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use syntect::highlighting::ThemeSet;
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::SyntaxSet;
+use crate::articles::{format_manifest_date, Manifest};
+use tracing::error;
+
+// Layout wrapping every rendered Markdown article, placeholders are written {{name}}
+const ARTICLE_LAYOUT: &str = "orailix.com/_layouts/article.html";
+const HIGHLIGHT_THEME: &str = "InspiredGitHub";
+
+// Syntax definitions and themes are costly to load, so we only do it once
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+// Split an index.md between its front matter (delimited by `---` lines) and its Markdown body
+pub fn split_front_matter(content: &str) -> Option<(Manifest, &str)> {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+    let rest = content.strip_prefix("---")?.trim_start_matches([' ', '\t']);
+    let rest = rest.strip_prefix("\r\n").or_else(|| rest.strip_prefix('\n'))?;
+
+    let mut manifest = Manifest::default();
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim_end();
+        if line == "---" {
+            return Some((manifest, &rest[offset..]));
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            manifest.set(key, value);
+        }
+    }
+    // The closing delimiter is missing
+    None
+}
+
+// Read the front matter of the index.md present in an article folder
+pub fn read_front_matter(article_path: &Path) -> Option<Manifest> {
+    let content = fs::read_to_string(article_path.join("index.md")).ok()?;
+    let (manifest, _) = split_front_matter(&content)?;
+    match manifest.title.is_empty() {
+        true => None,
+        false => Some(manifest),
+    }
+}
+
+// Render the index.md of an article folder into a full page using the article layout
+pub fn render_article(article_path: &Path) -> Option<String> {
+    let content = fs::read_to_string(article_path.join("index.md")).ok()?;
+    let (manifest, body) = split_front_matter(&content)?;
+    let category = article_path.parent()
+        .and_then(|category| category.file_name())
+        .map(|category| category.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    fill_article_layout(&manifest, &category, &markdown_to_html(body))
+}
+
+// Fill the article layout with the manifest of an article and its HTML content
+pub fn fill_article_layout(manifest: &Manifest, category: &str, content: &str) -> Option<String> {
+    let layout = match fs::read_to_string(ARTICLE_LAYOUT) {
+        Ok(layout) => layout,
+        Err(e) => {
+            error!("{e:?}: {ARTICLE_LAYOUT}");
+            return None
+        }
+    };
+
+    let formatted_date = format_manifest_date(&manifest.date).unwrap_or(manifest.date.clone());
+    let authors = match manifest.authors.is_empty() {
+        true => "Orailix".to_string(),
+        false => manifest.authors.join(", "),
+    };
+
+    // The content is inserted last so that placeholders written in the article itself are left untouched
+    let page = layout
+        .replace("{{title}}", &escape_html(&manifest.title))
+        .replace("{{summary}}", &escape_html(&manifest.summary))
+        .replace("{{authors}}", &escape_html(&authors))
+        .replace("{{formatted_date}}", &escape_html(&formatted_date))
+        .replace("{{category}}", &escape_html(category))
+        .replace("{{content}}", content);
+    Some(page)
+}
+
+// CommonMark with tables, footnotes, math passthrough, highlighted code blocks and captioned images
+pub fn markdown_to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_MATH;
+    let mut events = Vec::new();
+    let mut code_block: Option<(String, String)> = None;
+    let mut paragraph: Option<Vec<Event>> = None;
+
+    for event in Parser::new_ext(markdown, options) {
+        // Code blocks are accumulated, then highlighted as a whole
+        if let Some((language, mut code)) = code_block.take() {
+            match event {
+                Event::End(TagEnd::CodeBlock) => events.push(Event::Html(highlight_code(&language, &code).into())),
+                Event::Text(text) => {
+                    code.push_str(&text);
+                    code_block = Some((language, code));
+                }
+                _ => code_block = Some((language, code)),
+            }
+            continue;
+        }
+
+        let event = match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or_default().to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((language, String::new()));
+                continue;
+            }
+            // Math is left for MathJax to typeset in the browser
+            Event::InlineMath(math) => Event::Html(format!(r#"<span class="math math-inline">\({}\)</span>"#, escape_html(&math)).into()),
+            Event::DisplayMath(math) => Event::Html(format!(r#"<span class="math math-display">\[{}\]</span>"#, escape_html(&math)).into()),
+            // Every inline script of the page gets the CSP nonce, so the ones written in an article are shown as text
+            Event::Html(html) | Event::InlineHtml(html) if has_script_tag(&html) => Event::Text(html),
+            event => event,
+        };
+
+        // Paragraphs are buffered to turn the ones holding a single image into captioned figures
+        match event {
+            Event::Start(Tag::Paragraph) => paragraph = Some(vec![event]),
+            Event::End(TagEnd::Paragraph) => {
+                let mut buffered = paragraph.take().unwrap_or_default();
+                buffered.push(event);
+                match image_figure(&buffered) {
+                    Some(figure) => events.push(Event::Html(figure.into())),
+                    None => events.extend(buffered),
+                }
+            }
+            event => match paragraph.as_mut() {
+                Some(buffered) => buffered.push(event),
+                None => events.push(event),
+            }
+        }
+    }
+
+    let mut output = String::new();
+    html::push_html(&mut output, events.into_iter());
+    output
+}
+
+fn has_script_tag(html: &str) -> bool {
+    html.to_ascii_lowercase().contains("<script")
+}
+
+// Build a <figure> if the paragraph only contains an image, the caption is its title or alt text
+fn image_figure(paragraph: &[Event]) -> Option<String> {
+    let inner: Vec<&Event> = paragraph[1..paragraph.len() - 1].iter()
+        .filter(|event| !matches!(event, Event::SoftBreak | Event::HardBreak))
+        .collect();
+    let (first, last) = (inner.first()?, inner.last()?);
+    let (url, title) = match (first, last) {
+        (Event::Start(Tag::Image { dest_url, title, .. }), Event::End(TagEnd::Image)) => (dest_url, title),
+        _ => return None,
+    };
+
+    let mut alt = String::new();
+    for event in &inner[1..inner.len() - 1] {
+        match event {
+            Event::Text(text) | Event::Code(text) => alt.push_str(text),
+            // Anything richer than text means this is not a plain image
+            _ => return None,
+        }
+    }
+
+    let caption: &str = match title.is_empty() {
+        true => &alt,
+        false => title,
+    };
+    let figcaption = match caption.is_empty() {
+        true => String::new(),
+        false => format!("<figcaption>{}</figcaption>", escape_html(caption)),
+    };
+    Some(format!(
+        r#"<figure class="w-richtext-align-fullwidth w-richtext-figure-type-image"><div><img src="{}" loading="lazy" alt="{}"/></div>{}</figure>"#,
+        escape_html(url), escape_html(&alt), figcaption
+    ))
+}
+
+fn highlight_code(language: &str, code: &str) -> String {
+    let syntaxes = syntax_set();
+    let syntax = match language.is_empty() {
+        true => None,
+        false => syntaxes.find_syntax_by_token(language),
+    };
+    let theme = &theme_set().themes[HIGHLIGHT_THEME];
+    match syntax.map(|syntax| highlighted_html_for_string(code, syntaxes, syntax, theme)) {
+        Some(Ok(highlighted)) => highlighted,
+        _ => format!("<pre><code>{}</code></pre>\n", escape_html(code)),
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_the_scripts_of_raw_html() {
+        let html = markdown_to_html("Text <script>alert(2)</script> <b>bold</b>\n\n<div>kept</div>\n\n<SCRIPT\nsrc=x>\nalert(1)</SCRIPT>\n");
+        assert!(html.contains("<div>kept</div>"));
+        assert!(html.contains("<b>bold</b>"));
+        assert!(!html.to_ascii_lowercase().contains("<script"));
+        assert!(html.contains("&lt;SCRIPT\n"));
+        assert!(html.contains("&lt;script&gt;alert(2)"));
+    }
+}
@@ -0,0 +1,289 @@
+use std::path::Path;
+use chrono::NaiveDate;
+use serde_json::{json, Value};
+use crate::articles::{parse_manifest, Manifest};
+use crate::markdown::escape_html;
+
+const SITE_NAME: &str = "ORAILIX";
+const SITE_LOGO: &str = "/assets/img/orailix_logo.png";
+
+// Add OpenGraph, Twitter Card and JSON-LD metadata to the <head> of an article page served from
+// orailix.com/news/<category>/<folder>/, pages which are not articles are returned untouched
+pub fn inject_article_metadata(file_location: &str, page: Vec<u8>, public_origin: &str) -> Vec<u8> {
+    let (category, folder) = match article_location(file_location) {
+        Some(location) => location,
+        None => return page,
+    };
+    let manifest = match parse_manifest(&Path::new("orailix.com/news").join(category).join(folder)) {
+        Some(manifest) => manifest,
+        None => return page,
+    };
+    let html = match String::from_utf8(page) {
+        Ok(html) => html,
+        Err(e) => return e.into_bytes(),
+    };
+
+    // Hand-written tags are often copied from another article, we replace them with the manifest's
+    let html = remove_social_tags(&html);
+    let head_end = match find_head_end(&html) {
+        Some(index) => index,
+        None => return html.into_bytes(),
+    };
+
+    let description = match manifest.summary.is_empty() {
+        true => meta_content(&html, "name", "description").map(unescape_html).unwrap_or(manifest.title.clone()),
+        false => manifest.summary.clone(),
+    };
+    let tags = article_head_tags(&manifest, category, folder, &description, public_origin);
+
+    let mut injected = String::with_capacity(html.len() + tags.len());
+    injected.push_str(&html[..head_end]);
+    injected.push_str(&tags);
+    injected.push_str(&html[head_end..]);
+    injected.into_bytes()
+}
+
+// Extract the category and folder of an article page such as orailix.com/news/tech/2025-05-09-1-year/index.html
+fn article_location(file_location: &str) -> Option<(&str, &str)> {
+    let parts = file_location.strip_prefix("orailix.com/news/")?.split('/').collect::<Vec<&str>>();
+    match parts.as_slice() {
+        [category, folder, page] if page.ends_with(".html") && !category.is_empty() && !folder.is_empty() => Some((category, folder)),
+        _ => None,
+    }
+}
+
+fn article_head_tags(manifest: &Manifest, category: &str, folder: &str, description: &str, public_origin: &str) -> String {
+    let url = format!("{public_origin}/news/{category}/{folder}/");
+    let image = picture_url(manifest, category, folder, public_origin);
+    let published = NaiveDate::parse_from_str(&manifest.date, "%Y-%d-%m").ok().map(|date| date.format("%Y-%m-%d").to_string());
+
+    let mut tags = String::from("    <!-- Link previews, generated from the article manifest -->\n");
+    let mut push_tag = |attribute: &str, key: &str, value: &str| {
+        tags.push_str(&format!("    <meta {attribute}=\"{key}\" content=\"{}\">\n", escape_html(value)));
+    };
+    push_tag("property", "og:type", "article");
+    push_tag("property", "og:site_name", SITE_NAME);
+    push_tag("property", "og:url", &url);
+    push_tag("property", "og:title", &manifest.title);
+    push_tag("property", "og:description", description);
+    if let Some(ref image) = image {
+        push_tag("property", "og:image", image);
+    }
+    if let Some(ref published) = published {
+        push_tag("property", "article:published_time", published);
+    }
+    for author in &manifest.authors {
+        push_tag("property", "article:author", author);
+    }
+    push_tag("name", "twitter:card", if image.is_some() { "summary_large_image" } else { "summary" });
+    push_tag("name", "twitter:title", &manifest.title);
+    push_tag("name", "twitter:description", description);
+    if let Some(ref image) = image {
+        push_tag("name", "twitter:image", image);
+    }
+
+    let json_ld = article_json_ld(manifest, category, &url, description, image.as_deref(), published.as_deref(), public_origin);
+    // A "</script>" inside a JSON string would close the tag early
+    let json_ld = json_ld.to_string().replace("</", "<\\/");
+    tags.push_str(&format!("    <script type=\"application/ld+json\">{json_ld}</script>\n"));
+    tags
+}
+
+fn article_json_ld(manifest: &Manifest, category: &str, url: &str, description: &str, image: Option<&str>, published: Option<&str>, public_origin: &str) -> Value {
+    let organization = json!({
+        "@type": "Organization",
+        "name": SITE_NAME,
+        "url": public_origin,
+        "logo": { "@type": "ImageObject", "url": format!("{public_origin}{SITE_LOGO}") },
+    });
+    let authors = match manifest.authors.is_empty() {
+        true => json!([organization]),
+        false => Value::Array(manifest.authors.iter().map(|author| json!({ "@type": "Person", "name": author })).collect()),
+    };
+
+    let mut json_ld = json!({
+        "@context": "https://schema.org",
+        "@type": if category == "publications" { "ScholarlyArticle" } else { "NewsArticle" },
+        "headline": manifest.title,
+        "description": description,
+        "url": url,
+        "mainEntityOfPage": url,
+        "author": authors,
+        "publisher": organization,
+    });
+    if let Some(image) = image {
+        json_ld["image"] = json!([image]);
+    }
+    if let Some(published) = published {
+        json_ld["datePublished"] = json!(published);
+    }
+    json_ld
+}
+
+// Absolute URL of the preview picture, resolved the same way as in get_articles_handler
+fn picture_url(manifest: &Manifest, category: &str, folder: &str, public_origin: &str) -> Option<String> {
+    let picture = manifest.picture.trim_start_matches('/');
+    if picture.is_empty() {
+        None
+    } else if picture.starts_with("http") {
+        Some(picture.to_string())
+    } else if !manifest.page.is_empty() {
+        // Manifests with a page give paths relative to the news section
+        Some(format!("{public_origin}/news/{picture}"))
+    } else {
+        Some(format!("{public_origin}/news/{category}/{folder}/{picture}"))
+    }
+}
+
+// Index of the closing </head> tag, the generated tags come after the page's own (eg its charset)
+fn find_head_end(html: &str) -> Option<usize> {
+    html.to_ascii_lowercase().find("</head>")
+}
+
+// Iterate over the <meta ...> tags of a page, with their byte range
+fn meta_tags(html: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut from = 0;
+    std::iter::from_fn(move || {
+        let start = from + html[from..].find("<meta")?;
+        let end = start + html[start..].find('>')? + 1;
+        from = end;
+        Some((start, end))
+    })
+}
+
+// Value of an attribute in a single tag, eg attribute(r#"<meta name="x">"#, "name") == Some("x")
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(index) = rest.find(name) {
+        let preceded_by_space = rest[..index].ends_with(char::is_whitespace);
+        let after = rest[index + name.len()..].trim_start();
+        rest = &rest[index + name.len()..];
+        let value = match (preceded_by_space, after.strip_prefix('=')) {
+            (true, Some(value)) => value.trim_start(),
+            _ => continue,
+        };
+        let quote = value.chars().next()?;
+        return match quote {
+            '"' | '\'' => value[1..].split(quote).next(),
+            _ => value.split(|c: char| c.is_whitespace() || c == '>' || c == '/').next(),
+        }
+    }
+    None
+}
+
+fn meta_content<'a>(html: &'a str, attribute_name: &str, value: &str) -> Option<&'a str> {
+    meta_tags(html)
+        .map(|(start, end)| &html[start..end])
+        .find(|tag| attribute(tag, attribute_name) == Some(value))
+        .and_then(|tag| attribute(tag, "content"))
+}
+
+fn remove_social_tags(html: &str) -> String {
+    let mut cleaned = String::with_capacity(html.len());
+    let mut copied = 0;
+    for (start, end) in meta_tags(html) {
+        let tag = &html[start..end];
+        let key = attribute(tag, "property").or_else(|| attribute(tag, "name")).unwrap_or_default();
+        if key.starts_with("og:") || key.starts_with("twitter:") || key.starts_with("article:") {
+            cleaned.push_str(html[copied..start].trim_end_matches([' ', '\t']));
+            copied = end;
+            // Drop the line break left by the removed tag
+            if html[copied..].starts_with('\n') {
+                copied += 1;
+            }
+        }
+    }
+    cleaned.push_str(&html[copied..]);
+    cleaned
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const PUBLIC_ORIGIN: &str = "https://orailix.com";
+
+    // Removes the article written by a test
+    struct ArticleFolder(PathBuf);
+
+    impl Drop for ArticleFolder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // An article of the talks category with the given manifest, and the location of its page
+    fn article(name: &str, manifest: &str) -> (ArticleFolder, String) {
+        let folder = format!("test-metadata-{name}-{}", std::process::id());
+        let article = ArticleFolder(Path::new("orailix.com/news/talks").join(&folder));
+        fs::create_dir_all(&article.0).unwrap();
+        fs::write(article.0.join("manifest.txt"), manifest).unwrap();
+        (article, format!("orailix.com/news/talks/{folder}/index.html"))
+    }
+
+    fn inject(location: &str, page: &str) -> String {
+        String::from_utf8(inject_article_metadata(location, page.as_bytes().to_vec(), PUBLIC_ORIGIN)).unwrap()
+    }
+
+    #[test]
+    fn reads_quoted_and_unquoted_attributes() {
+        assert_eq!(attribute(r#"<meta name="description" content='A "quoted" text'>"#, "content"), Some(r#"A "quoted" text"#));
+        assert_eq!(attribute(r#"<meta name="description" content='A "quoted" text'>"#, "name"), Some("description"));
+        assert_eq!(attribute("<meta name=description content=x>", "name"), Some("description"));
+        assert_eq!(attribute("<meta property=og:title/>", "property"), Some("og:title"));
+        assert_eq!(attribute(r#"<meta name = "x">"#, "name"), Some("x"));
+        // Only whole attribute names match
+        assert_eq!(attribute(r#"<meta data-name="x" name="y">"#, "name"), Some("y"));
+        assert_eq!(attribute(r#"<meta content="x">"#, "name"), None);
+    }
+
+    #[test]
+    fn finds_the_meta_tags_and_the_end_of_the_head() {
+        let html = "<HTML><HEAD><meta charset=utf-8><title>t</title>\n<meta name=\"x\" content=\"y\"></HEAD><body><meta></body>";
+        let tags: Vec<&str> = meta_tags(html).map(|(start, end)| &html[start..end]).collect();
+        assert_eq!(tags, ["<meta charset=utf-8>", "<meta name=\"x\" content=\"y\">", "<meta>"]);
+        assert_eq!(find_head_end(html), html.find("</HEAD>"));
+        assert_eq!(find_head_end("<html><body></body></html>"), None);
+    }
+
+    #[test]
+    fn removes_the_hand_written_social_tags() {
+        let html = "<head>\n    <meta charset=\"utf-8\">\n    <meta property=\"og:title\" content=\"Another article\">\n\t<meta name=twitter:card content=summary>\n    <meta property=\"article:author\" content=\"x\">\n    <meta name=\"description\" content=\"d\">\n</head>";
+        assert_eq!(remove_social_tags(html), "<head>\n    <meta charset=\"utf-8\">\n    <meta name=\"description\" content=\"d\">\n</head>");
+    }
+
+    #[test]
+    fn replaces_the_social_tags_of_an_article() {
+        let (_article, location) = article("replaced", "title=Fast & \"safe\" <models></script>\ndate=2024-14-08\nauthors=Ada, Bob\n");
+        let page = "<!DOCTYPE html>\n<HTML><HEAD>\n    <meta name=\"description\" content=\"Less &lt;b&gt; &amp; more\">\n    <meta property=\"og:title\" content=\"Copied from another article\">\n</HEAD><body></body></HTML>";
+        let html = inject(&location, page);
+        assert!(!html.contains("Copied from another article"));
+        assert!(html.contains(r#"<meta property="og:title" content="Fast &amp; &quot;safe&quot; &lt;models&gt;&lt;/script&gt;">"#));
+        assert!(html.contains(r#"<meta name="twitter:description" content="Less &lt;b&gt; &amp; more">"#));
+        assert!(html.contains(r#"<meta property="article:published_time" content="2024-08-14">"#));
+        assert!(html.contains(r#"<meta property="article:author" content="Bob">"#));
+        // The title cannot close the JSON-LD script
+        assert!(html.contains(r#""headline":"Fast & \"safe\" <models><\/script>""#));
+        assert_eq!(html.matches("</script>").count(), 1);
+        assert!(html.ends_with("</script>\n</HEAD><body></body></HTML>"));
+    }
+
+    #[test]
+    fn leaves_the_pages_without_head_or_manifest() {
+        let (_article, location) = article("headless", "title=Headless\ndate=2024-14-08\n");
+        assert!(!inject(&location, "<p>No head</p>").contains("og:type"));
+        let page = "<head><meta property=\"og:title\" content=\"Kept\"></head>";
+        assert_eq!(inject("orailix.com/news/talks/index.html", page), page);
+        assert_eq!(inject("orailix.com/news/talks/missing-article/index.html", page), page);
+    }
+}
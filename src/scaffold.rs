@@ -0,0 +1,201 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use chrono::{Local, NaiveDate};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::ImageFormat;
+use crate::articles::Manifest;
+use crate::markdown::fill_article_layout;
+use crate::validate::{validate_article, NEWS_PATH};
+
+const STARTER_MARKDOWN: &str = "orailix.com/_layouts/starter.md";
+// Preview pictures wider than this are downscaled, the article grid never displays them larger
+const MAX_PICTURE_WIDTH: u32 = 1600;
+const JPEG_QUALITY: u8 = 85;
+
+pub struct NewArticle {
+    pub category: String,
+    pub title: String,
+    pub date: Option<String>,
+    pub slug: Option<String>,
+    pub url: Option<String>,
+    pub picture: Option<String>,
+    pub html: bool,
+}
+
+// Create an article folder named after its date and title, with its manifest, a starter page and its preview picture.
+// Returns the created folder, which has been checked by the content validator
+pub fn new_article(article: &NewArticle) -> Result<String, String> {
+    let category_path = Path::new(NEWS_PATH).join(&article.category);
+    if !category_path.is_dir() {
+        return Err(format!("unknown category '{}', expected one of the folders of {NEWS_PATH}", article.category))
+    }
+
+    // Dates are given as YYYY-MM-DD but the folders and manifests use YYYY-DD-MM
+    let date = match &article.date {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|e| format!("invalid date '{date}', expected YYYY-MM-DD: {e}"))?,
+        None => Local::now().date_naive(),
+    };
+    let date = date.format("%Y-%d-%m").to_string();
+    let slug = match &article.slug {
+        Some(slug) => slugify(slug),
+        None => slugify(&article.title),
+    };
+    let folder = match slug.is_empty() {
+        true => date.clone(),
+        false => format!("{date}-{slug}"),
+    };
+    let article_path = category_path.join(&folder);
+    if article_path.exists() {
+        return Err(format!("{} already exists", article_path.display()))
+    }
+
+    // Manifests with a page give paths relative to the news section, the others relative to the article folder
+    let relative_to_news = article.url.is_some() || article.html;
+    let picture_name = match &article.picture {
+        Some(picture) => Some(picture_file_name(picture)?),
+        None => None,
+    };
+    let picture = match (&picture_name, relative_to_news) {
+        (Some(name), true) => format!("{}/{folder}/{name}", article.category),
+        (Some(name), false) => name.clone(),
+        (None, _) => String::new(),
+    };
+    let page = match (&article.url, article.html) {
+        (Some(url), _) => url.clone(),
+        (None, true) => format!("{}/{folder}/index.html", article.category),
+        (None, false) => String::new(),
+    };
+
+    fs::create_dir(&article_path).map_err(|e| format!("cannot create {}: {e}", article_path.display()))?;
+    // A failure leaves no half-created folder behind, so that the command can simply be run again
+    let created = fill_article_folder(article, &article_path, picture_name.as_deref(), &date, &picture, &page)
+        .and_then(|_| {
+            let report = validate_article(&article_path);
+            report.print(&article_path);
+            match report.is_valid() {
+                true => Ok(article_path.display().to_string()),
+                false => Err(format!("{} is not valid", article_path.display())),
+            }
+        });
+    if created.is_err() {
+        if let Err(e) = fs::remove_dir_all(&article_path) {
+            println!("cannot remove {}: {e}", article_path.display());
+        }
+    }
+    created
+}
+
+// The picture, manifest and starter page of a new article
+fn fill_article_folder(article: &NewArticle, article_path: &Path, picture_name: Option<&str>, date: &str, picture: &str, page: &str) -> Result<(), String> {
+    if let (Some(source), Some(name)) = (&article.picture, picture_name) {
+        optimize_picture(Path::new(source), &article_path.join(name))?;
+    }
+
+    // External articles only need their manifest, local ones get a starter page
+    let mut manifest = Manifest::default();
+    manifest.set("title", &article.title);
+    manifest.set("date", date);
+    manifest.set("picture", picture);
+    manifest.set("page", page);
+    if article.url.is_some() || article.html {
+        let content = format!("title={}\ndate={date}\npicture={picture}\npage={page}\n", article.title);
+        write(&article_path.join("manifest.txt"), &content)?;
+    }
+    if article.url.is_none() && article.html {
+        let starter = "<p>Write your article here.</p>";
+        let page = fill_article_layout(&manifest, &article.category, starter).ok_or("cannot read the article layout")?;
+        write(&article_path.join("index.html"), &page)?;
+    } else if article.url.is_none() {
+        let template = fs::read_to_string(STARTER_MARKDOWN).map_err(|e| format!("cannot read {STARTER_MARKDOWN}: {e}"))?;
+        let page = template
+            .replace("{{title}}", &article.title)
+            .replace("{{date}}", date)
+            .replace("{{picture}}", picture);
+        write(&article_path.join("index.md"), &page)?;
+    }
+    Ok(())
+}
+
+// Lowercase ASCII words separated by dashes, as in the existing folder names
+pub(crate) fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().map(fold_accent).flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    // Keep folder names short, cutting at a word boundary
+    let mut slug = slug.trim_end_matches('-').to_string();
+    while slug.len() > 40 {
+        match slug.rfind('-') {
+            Some(index) => slug.truncate(index),
+            None => slug.truncate(40),
+        }
+    }
+    slug
+}
+
+fn fold_accent(c: char) -> char {
+    match c {
+        'à' | 'â' | 'ä' | 'á' | 'ã' | 'å' => 'a',
+        'À' | 'Â' | 'Ä' | 'Á' | 'Ã' | 'Å' => 'A',
+        'é' | 'è' | 'ê' | 'ë' => 'e',
+        'É' | 'È' | 'Ê' | 'Ë' => 'E',
+        'î' | 'ï' | 'í' | 'ì' => 'i',
+        'Î' | 'Ï' | 'Í' | 'Ì' => 'I',
+        'ô' | 'ö' | 'ó' | 'ò' | 'õ' => 'o',
+        'Ô' | 'Ö' | 'Ó' | 'Ò' | 'Õ' => 'O',
+        'û' | 'ü' | 'ú' | 'ù' => 'u',
+        'Û' | 'Ü' | 'Ú' | 'Ù' => 'U',
+        'ç' => 'c',
+        'Ç' => 'C',
+        'ñ' => 'n',
+        'Ñ' => 'N',
+        c => c,
+    }
+}
+
+// The preview picture is stored as image.<extension> next to the manifest
+fn picture_file_name(source: &str) -> Result<String, String> {
+    let extension = Path::new(source).extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .ok_or(format!("picture '{source}' has no extension"))?;
+    match extension.as_str() {
+        "jpg" | "jpeg" => Ok("image.jpg".to_string()),
+        "png" | "webp" => Ok(format!("image.{extension}")),
+        _ => Err(format!("unsupported picture format '{extension}', use jpg, png or webp")),
+    }
+}
+
+// Copy the picture, downscaling it when it is wider than needed
+fn optimize_picture(source: &Path, destination: &Path) -> Result<(), String> {
+    let (width, _) = image::image_dimensions(source).map_err(|e| format!("cannot read picture {}: {e}", source.display()))?;
+    if width <= MAX_PICTURE_WIDTH {
+        fs::copy(source, destination).map_err(|e| format!("cannot copy picture to {}: {e}", destination.display()))?;
+        return Ok(())
+    }
+
+    let picture = image::open(source).map_err(|e| format!("cannot decode picture {}: {e}", source.display()))?;
+    let resized = picture.resize(MAX_PICTURE_WIDTH, u32::MAX, FilterType::Lanczos3);
+    let format = ImageFormat::from_path(destination).map_err(|e| e.to_string())?;
+    let result = match format {
+        ImageFormat::Jpeg => {
+            let file = File::create(destination).map_err(|e| e.to_string())?;
+            resized.to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(BufWriter::new(file), JPEG_QUALITY))
+        }
+        // WebP is re-encoded losslessly, the only WebP encoder available
+        _ => resized.save_with_format(destination, format),
+    };
+    result.map_err(|e| format!("cannot write picture {}: {e}", destination.display()))?;
+    println!("Picture downscaled from {width}px to {MAX_PICTURE_WIDTH}px wide");
+    Ok(())
+}
+
+fn write(path: &Path, content: &str) -> Result<(), String> {
+    fs::write(path, content).map_err(|e| format!("cannot write {}: {e}", path.display()))
+}
+
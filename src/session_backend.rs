@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, Utc};
+use futures_util::{future, FutureExt};
+use gotham::anyhow;
+use gotham::middleware::session::{Backend, MemoryBackend, NewBackend, SessionError, SessionIdentifier};
+use crate::config::SessionsConfig;
+use crate::users;
+use tracing::error;
+
+type SessionFuture = dyn Future<Output = Result<Option<Vec<u8>>, SessionError>> + Send;
+
+// Date of the last revocation of the sessions of each user, in RFC 3339
+type Revocations = HashMap<String, String>;
+
+// Where the sessions are kept, selected by [sessions] backend in the configuration
+#[derive(Clone)]
+pub enum SessionBackend {
+    // Lost on restart, and not shared between instances
+    Memory(MemoryBackend, Arc<Mutex<Revocations>>),
+    // Logged in sessions survive restarts and are shared by the instances using the same folder
+    File(FileBackend),
+}
+
+impl SessionBackend {
+    pub fn from_config(config: &SessionsConfig, ttl: Duration) -> Result<Self, String> {
+        match config.backend.as_str() {
+            "memory" => Ok(SessionBackend::Memory(MemoryBackend::new(ttl), Arc::default())),
+            "file" => FileBackend::new(&config.dir, ttl).map(SessionBackend::File),
+            backend => Err(format!("unknown session backend '{backend}', expected memory or file")),
+        }
+    }
+
+    // Delete the expired sessions every gc_interval, from the server only
+    pub fn collect_garbage_every(&self, gc_interval: Duration) {
+        if let SessionBackend::File(backend) = self {
+            let collector = backend.clone();
+            std::thread::spawn(move || loop {
+                collector.collect_garbage();
+                std::thread::sleep(gc_interval);
+            });
+        }
+    }
+
+    // Every stored session with its identifier, the in-memory sessions cannot be listed
+    pub fn sessions(&self) -> Vec<(String, Vec<u8>)> {
+        match self {
+            SessionBackend::Memory(..) => Vec::new(),
+            SessionBackend::File(backend) => backend.sessions(),
+        }
+    }
+
+    // Log out the sessions of user_id started until now, including those an in-flight request writes back later
+    pub fn revoke_sessions(&self, user_id: &str) -> Result<(), String> {
+        match self {
+            SessionBackend::Memory(_, revocations) => {
+                revocations.lock().map_err(|e| e.to_string())?.insert(user_id.to_string(), Utc::now().to_rfc3339());
+                Ok(())
+            }
+            SessionBackend::File(backend) => backend.revoke_sessions(user_id),
+        }
+    }
+
+    // Date of the last revocation of the sessions of user_id
+    pub fn revoked_at(&self, user_id: &str) -> Option<DateTime<Utc>> {
+        let revoked_at = match self {
+            SessionBackend::Memory(_, revocations) => revocations.lock().ok()?.get(user_id).cloned(),
+            SessionBackend::File(backend) => backend.revocations().remove(user_id),
+        }?;
+        DateTime::parse_from_rfc3339(&revoked_at).ok().map(|revoked_at| revoked_at.with_timezone(&Utc))
+    }
+}
+
+impl NewBackend for SessionBackend {
+    type Instance = Self;
+
+    fn new_backend(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl Backend for SessionBackend {
+    fn persist_session(&self, identifier: SessionIdentifier, content: &[u8]) -> Result<(), SessionError> {
+        match self {
+            SessionBackend::Memory(backend, _) => backend.persist_session(identifier, content),
+            SessionBackend::File(backend) => backend.persist_session(identifier, content),
+        }
+    }
+
+    fn read_session(&self, identifier: SessionIdentifier) -> Pin<Box<SessionFuture>> {
+        match self {
+            SessionBackend::Memory(backend, _) => backend.read_session(identifier),
+            SessionBackend::File(backend) => backend.read_session(identifier),
+        }
+    }
+
+    fn drop_session(&self, identifier: SessionIdentifier) -> Result<(), SessionError> {
+        match self {
+            SessionBackend::Memory(backend, _) => backend.drop_session(identifier),
+            SessionBackend::File(backend) => backend.drop_session(identifier),
+        }
+    }
+}
+
+// bincode serialization of a session without login, Option::<LoginData>::None
+const ANONYMOUS_SESSION: [u8; 1] = [0];
+
+// Revocation dates shared by the instances using the session folder
+const REVOCATIONS_FILE: &str = "revocations.json";
+
+// One file per logged in session, named after its identifier.
+// Every visitor gets a session, the anonymous ones hold nothing worth keeping and stay in memory
+#[derive(Clone)]
+pub struct FileBackend {
+    dir: PathBuf,
+    ttl: Duration,
+    anonymous: MemoryBackend,
+}
+
+impl FileBackend {
+    pub fn new(dir: &str, ttl: Duration) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create the session folder {dir}: {e}"))?;
+        Ok(Self { dir: PathBuf::from(dir), ttl, anonymous: MemoryBackend::new(ttl) })
+    }
+
+    fn sessions(&self) -> Vec<(String, Vec<u8>)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| !self.is_expired(path))
+            .filter_map(|path| {
+                let identifier = path.file_name()?.to_str().filter(|name| is_valid_identifier(name))?.to_string();
+                Some((identifier, fs::read(&path).ok()?))
+            })
+            .collect()
+    }
+
+    fn collect_garbage(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("cannot read the session folder {}: {e}", self.dir.display());
+                return
+            }
+        };
+        for path in entries.flatten().map(|entry| entry.path()).filter(|path| is_session_file(path) && self.is_expired(path)) {
+            if let Err(e) = fs::remove_file(&path) {
+                error!("cannot remove the expired session {}: {e}", path.display());
+            }
+        }
+    }
+
+    fn revocations(&self) -> Revocations {
+        fs::read(self.dir.join(REVOCATIONS_FILE)).ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default()
+    }
+
+    // The revocations older than the lifetime of the sessions no longer concern any of them and are dropped
+    fn revoke_sessions(&self, user_id: &str) -> Result<(), String> {
+        let path = self.dir.join(REVOCATIONS_FILE);
+        let _lock = users::lock_file(&path.to_string_lossy())?;
+        let now = Utc::now();
+        let mut revocations = self.revocations();
+        revocations.retain(|_, revoked_at| DateTime::parse_from_rfc3339(revoked_at)
+            .is_ok_and(|revoked_at| (now - revoked_at.with_timezone(&Utc)).to_std().map_or(true, |age| age <= self.ttl)));
+        revocations.insert(user_id.to_string(), now.to_rfc3339());
+        let content = serde_json::to_vec(&revocations).map_err(|e| e.to_string())?;
+        users::write_private(&path, &content).map_err(|e| format!("cannot write {}: {e}", path.display()))
+    }
+
+    // Sessions are written on every authenticated request, so their age is their idle time
+    fn is_expired(&self, path: &Path) -> bool {
+        fs::metadata(path).and_then(|metadata| metadata.modified())
+            .map(|modified| SystemTime::now().duration_since(modified).unwrap_or_default() > self.ttl)
+            .unwrap_or(true)
+    }
+
+    // The identifier comes from a cookie, it must not be able to point outside of the session folder
+    fn session_path(&self, identifier: &SessionIdentifier) -> Result<PathBuf, SessionError> {
+        match is_valid_identifier(&identifier.value) {
+            true => Ok(self.dir.join(&identifier.value)),
+            false => Err(SessionError::Backend("invalid session identifier".to_string())),
+        }
+    }
+
+    fn persist_session(&self, identifier: SessionIdentifier, content: &[u8]) -> Result<(), SessionError> {
+        let path = self.session_path(&identifier)?;
+        if content == ANONYMOUS_SESSION {
+            // A session logged out or expired leaves the disk
+            remove_session_file(&path)?;
+            return self.anonymous.persist_session(identifier, content)
+        }
+        self.anonymous.drop_session(identifier)?;
+
+        // Write to a temporary file first, so that concurrent requests never read a partial session
+        static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+        let temporary = path.with_extension(format!("{}.tmp", TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&temporary, content)
+            .and_then(|_| fs::rename(&temporary, &path))
+            .map_err(|e| SessionError::Backend(format!("cannot write {}: {e}", path.display())))
+    }
+
+    fn read_session(&self, identifier: SessionIdentifier) -> Pin<Box<SessionFuture>> {
+        let path = match self.session_path(&identifier) {
+            Ok(path) => path,
+            // An invalid identifier is an unknown session, the middleware starts a new one
+            Err(_) => return future::ok(None).boxed(),
+        };
+        if self.is_expired(&path) {
+            return self.anonymous.read_session(identifier)
+        }
+        match fs::read(&path) {
+            Ok(content) => future::ok(Some(content)).boxed(),
+            Err(_) => self.anonymous.read_session(identifier),
+        }
+    }
+
+    fn drop_session(&self, identifier: SessionIdentifier) -> Result<(), SessionError> {
+        if let Ok(path) = self.session_path(&identifier) {
+            remove_session_file(&path)?;
+        }
+        self.anonymous.drop_session(identifier)
+    }
+}
+
+// Identifiers are base64url (session middleware) or hexadecimal (login)
+fn is_valid_identifier(identifier: &str) -> bool {
+    !identifier.is_empty() && identifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Sessions and their temporary files, unlike the revocations and their lock
+fn is_session_file(path: &Path) -> bool {
+    path.file_name().and_then(|name| name.to_str())
+        .is_some_and(|name| is_valid_identifier(name) || name.ends_with(".tmp"))
+}
+
+fn remove_session_file(path: &Path) -> Result<(), SessionError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(SessionError::Backend(format!("cannot remove {}: {e}", path.display()))),
+        _ => Ok(()),
+    }
+}
@@ -1,14 +1,32 @@
 use serde::{Deserialize, Serialize};
-use gotham::state::{FromState, State};
-use chrono::Utc;
+use gotham::state::{client_addr, FromState, State};
+use chrono::{DateTime, Utc};
 use gotham_derive::StateData;
 use gotham::helpers::http::response::create_response;
 use gotham::handler::HandlerFuture;
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
-use mime::{TEXT_HTML, TEXT_PLAIN};
-use gotham::hyper::{body, Body, Response, Uri, StatusCode};
+use std::sync::{Arc, Mutex};
+use mime::APPLICATION_JSON;
+use gotham::hyper::{body, Body, HeaderMap, Response, StatusCode, Uri};
+use gotham::router::response::extender::StaticResponseExtender;
+use gotham::hyper::header::{CONTENT_TYPE, RETRY_AFTER, SET_COOKIE};
 use futures_util::{future, FutureExt};
-use gotham::middleware::session::{SessionData};
+use gotham::anyhow;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::middleware::cookie::CookieParser;
+use gotham::middleware::session::{Backend, SessionData, SessionIdentifier};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use crate::api_tokens::TokenLogin;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::config::{Config, SameSite};
+use crate::csrf;
+use crate::logging;
+use crate::session_backend::SessionBackend;
+use crate::throttle::{LoginThrottle, Penalty};
+use crate::users::{self, Role, SecondFactor, UserStore};
+use tracing::{debug_span, error, info, Span};
 
 
 
@@ -16,124 +34,589 @@ use gotham::middleware::session::{SessionData};
 pub struct LoginData {
     pub(crate) user_id: String,
     pub(crate) connected: bool,
-    last_interaction: String
+    // Roles of the user when they logged in, revoke their sessions to apply a change
+    pub(crate) roles: Vec<Role>,
+    // Whether the login was confirmed with a second factor, password-only sessions cannot use
+    // the roles from auth.second_factor_role up
+    pub(crate) two_factor: bool,
+    last_interaction: String,
+    logged_in_at: String,
 }
 
+impl LoginData {
+    pub(crate) fn new(user_id: &str, roles: Vec<Role>, two_factor: bool) -> Self {
+        let now = Utc::now().to_rfc3339();
+        Self {
+            user_id: user_id.to_string(),
+            connected: true,
+            roles,
+            two_factor,
+            last_interaction: now.clone(),
+            logged_in_at: now,
+        }
+    }
+
+    // Seconds left before the session expires, either because it is too old or because the user is idle
+    fn remaining_lifetime(&self, limits: &SessionLimits) -> Option<i64> {
+        let now = Utc::now();
+        let elapsed = |date: &str| DateTime::parse_from_rfc3339(date).ok().map(|date| (now - date.with_timezone(&Utc)).num_seconds());
+        let remaining = (limits.session_lifetime - elapsed(&self.logged_in_at)?).min(limits.idle_timeout - elapsed(&self.last_interaction)?);
+        match remaining > 0 {
+            true => Some(remaining),
+            false => None,
+        }
+    }
+}
+
+// Absolute and idle lifetimes of the sessions, in seconds
+#[derive(Clone, Copy, StateData)]
+pub struct SessionLimits {
+    pub session_lifetime: i64,
+    pub idle_timeout: i64,
+}
+
+// Logs out the sessions past their limits or revoked and keeps the others alive, for every request
+#[derive(Clone)]
+pub struct SessionExpiryMiddleware {
+    limits: SessionLimits,
+}
+
+impl SessionExpiryMiddleware {
+    pub(crate) fn new(limits: SessionLimits) -> Self {
+        Self { limits }
+    }
+}
+
+impl NewMiddleware for SessionExpiryMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl Middleware for SessionExpiryMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        // Polling the session status must not keep an idle session alive
+        let is_activity = Uri::borrow_from(&state).path() != SESSION_STATUS_ROUTE;
+        let identifier = session_identifier(&state);
+        let store = SessionStore::try_borrow_from(&state).cloned();
+        if let Some(session) = SessionData::<Option<LoginData>>::try_borrow_mut_from(&mut state) {
+            let login_data = session.as_ref().filter(|login_data| login_data.connected);
+            let remaining_lifetime = login_data.map(|login_data| {
+                let revoked = store.as_ref().is_some_and(|store| store.is_revoked(login_data));
+                (login_data.user_id.clone(), login_data.remaining_lifetime(&self.limits).filter(|_| !revoked))
+            });
+            match remaining_lifetime {
+                Some((user_id, None)) => {
+                    if let (Some(store), Some(identifier)) = (store, identifier) {
+                        store.untrack(&user_id, &identifier);
+                    }
+                    **session = None
+                }
+                Some((_, Some(_))) if is_activity => {
+                    if let Some(login_data) = session.as_mut() {
+                        login_data.last_interaction = Utc::now().to_rfc3339();
+                    }
+                }
+                _ => {}
+            }
+        }
+        state.put(self.limits);
+        chain(state)
+    }
+}
+
+pub const SESSION_STATUS_ROUTE: &str = "/api/session";
+
+// Name of the cookie holding the session identifier, the default one of gotham's session middleware
+const SESSION_COOKIE: &str = "_gotham_session";
+
+// Handle on the session backend shared with the session middleware, so that handlers can replace a session
+#[derive(Clone, StateData)]
+pub struct SessionStore {
+    backend: SessionBackend,
+    secure: bool,
+    same_site: SameSite,
+    // Identifiers of the logged in sessions of every user, to revoke them all at once
+    user_sessions: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+impl SessionStore {
+    pub(crate) fn new(backend: SessionBackend, secure: bool, same_site: SameSite) -> Self {
+        Self { backend, secure, same_site, user_sessions: Arc::default() }
+    }
+
+    fn track(&self, user_id: &str, identifier: &str) {
+        if let Ok(mut user_sessions) = self.user_sessions.lock() {
+            user_sessions.entry(user_id.to_string()).or_default().insert(identifier.to_string());
+        }
+    }
+
+    fn untrack(&self, user_id: &str, identifier: &str) {
+        if let Ok(mut user_sessions) = self.user_sessions.lock() {
+            if let Some(identifiers) = user_sessions.get_mut(user_id) {
+                identifiers.remove(identifier);
+                if identifiers.is_empty() {
+                    user_sessions.remove(user_id);
+                }
+            }
+        }
+    }
+
+    // Log a user out of every device, returns the number of revoked sessions.
+    // The revocation date also logs out the sessions written back by the requests in progress
+    pub(crate) fn revoke_user_sessions(&self, user_id: &str) -> Result<usize, String> {
+        self.backend.revoke_sessions(user_id)?;
+        let mut identifiers = match self.user_sessions.lock() {
+            Ok(mut user_sessions) => user_sessions.remove(user_id).unwrap_or_default(),
+            Err(e) => return Err(e.to_string()),
+        };
+        // A persistent backend also keeps the sessions of the other instances and of the previous runs
+        identifiers.extend(self.backend.sessions().into_iter()
+            .filter(|(_, content)| matches!(bincode::deserialize::<Option<LoginData>>(content), Ok(Some(login_data)) if login_data.user_id == user_id))
+            .map(|(identifier, _)| identifier));
+        for value in &identifiers {
+            self.backend.drop_session(SessionIdentifier { value: value.clone() }).map_err(|e| format!("{e:?}"))?;
+        }
+        Ok(identifiers.len())
+    }
+
+    // Whether the session started before the last revocation of the sessions of its user
+    fn is_revoked(&self, login_data: &LoginData) -> bool {
+        match (self.backend.revoked_at(&login_data.user_id), DateTime::parse_from_rfc3339(&login_data.logged_in_at)) {
+            (Some(revoked_at), Ok(logged_in_at)) => logged_in_at <= revoked_at,
+            _ => false,
+        }
+    }
+
+    // Same attributes as the cookies sent by the session middleware
+    pub(crate) fn cookie(&self, name: &str, value: &str) -> String {
+        let secure = match self.secure {
+            true => "; Secure",
+            false => "",
+        };
+        let same_site = match self.same_site {
+            SameSite::Lax => "Lax",
+            SameSite::Strict => "Strict",
+        };
+        format!("{name}={value}{secure}; HttpOnly; SameSite={same_site}; Path=/")
+    }
+}
+
+// Credentials sent to /api/login, either as a form or as JSON.
+// Users with a second factor add a TOTP or recovery code, without it their session is password-only
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+    #[serde(default)]
+    totp: Option<String>,
+}
+
+// Outcome of a login, a logout or an admin action
+#[derive(Serialize)]
+struct AuthResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // The CSRF token of the new session, the previous one is no longer valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    csrf_token: Option<String>,
+    // Whether the new session is confirmed by a second factor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    two_factor: Option<bool>,
+}
+
+impl AuthResponse {
+    fn success(user_id: Option<String>, csrf_token: String) -> Self {
+        Self { success: true, user_id, error: None, csrf_token: Some(csrf_token), two_factor: None }
+    }
+
+    fn failure(error: &str) -> Self {
+        Self { success: false, user_id: None, error: Some(error.to_string()), csrf_token: None, two_factor: None }
+    }
+}
+
+// Check the credentials against the user store and log the user in a brand new session
 pub fn connect_user(mut state: State) -> Pin<Box<HandlerFuture>> {
-    let f = body::to_bytes(Body::take_from(&mut state)).then(|full_body| match full_body {
-        Ok(valid_body) => {
-
-            println!("Entered 'connect_user' backend function");
-
-            // 1 - Parse the body's payload to extract the login credentials
-            // a - To get the URI, which is basically what was written in the request's URL you can extract it with:
-            //      let uri = Uri::borrow_from(&state).to_string();
-            //      The way it works is simply by using gotham's URI borrow function on the state, and turn it into a string.
-            //      From there you can do operations such as parsing, splitting etc to collect data.
-            //
-            // b - To get the internal payload, you can use:
-            //     let extracted_body = String::from_utf8(valid_body.to_vec()).unwrap();
-            //      It will extract the body of the sent request as a string, and again you can do operations on that.
-            //      This internal data is created from the user or browser by a XML request. We can do them in Javascript and you can
-            //      see an example in the login webpage at line XXX. We basically create a request by specifying a URL (which leads to connect_user)
-            //      and we add some payload data that you are trying to extract here.
-
-            // 2 - Checks if the credentials are correct
-            //      From the previously extracted body, parse the username and password and match it.
-            //      You can use the 'is_credential_valid' function to check if they are correct
-            // a - If they are wrong, you should return an error message in a string with a Status Error from the function
-            // b - If they are correct, you should proceed to 3)
-
-            // 3 - The credentials are correct, so you should change the internal state of LoginData's 'connected' value from false to true
-            //     For that, refer to 'https://github.com/gotham-rs/gotham/blob/main/examples/sessions/custom_data_type/src/main.rs'
-            //      especially at the lines 38-48 where the internal's state is extracted. In our case we are not using the 'VisitData' structure,
-            //      but the custom one called 'LoginData' that we have implemented ourselves.
-            //      You should change the internal state to true after verifying the credentials.
-
-
-            let response_payload = format!("your payload that you should create");
-            let mut res = create_response(&mut state, StatusCode::OK, TEXT_HTML, response_payload);
-            future::ok((state, res))
+    let span = debug_span!("connect_user");
+    let f = async move {
+        let body = match body::to_bytes(Body::take_from(&mut state)).await {
+            Ok(body) => body,
+            Err(e) => return Err((state, e.into())),
+        };
+
+        let is_json = HeaderMap::borrow_from(&state).get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        let credentials = match is_json {
+            true => serde_json::from_slice::<Credentials>(&body).map_err(|e| e.to_string()),
+            false => serde_urlencoded::from_bytes::<Credentials>(&body).map_err(|e| e.to_string()),
+        };
+
+        let mut retry_after = None;
+        let (status, payload, cookies) = match credentials {
+            Err(e) => (StatusCode::BAD_REQUEST, AuthResponse::failure(&format!("expected a username and a password: {e}")), Vec::new()),
+            Ok(credentials) => {
+                let username = credentials.username.clone();
+                let ip = client_addr(&state).map(|address| address.ip().to_string()).unwrap_or_default();
+                let keys = [LoginThrottle::ip_key(&ip), LoginThrottle::username_key(&username)];
+                retry_after = LoginThrottle::borrow_from(&state).retry_after(&keys);
+                match retry_after {
+                    Some(seconds) => {
+                        let error = format!("too many failed attempts, retry in {seconds} seconds");
+                        (StatusCode::TOO_MANY_REQUESTS, AuthResponse::failure(&error), Vec::new())
+                    }
+                    None => {
+                        let users_file = Config::borrow_from(&state).auth.users_file.clone();
+                        // Argon2 is deliberately slow, we keep it away from the server's threads
+                        let span = Span::current();
+                        let user = tokio::task::spawn_blocking(move || span.in_scope(|| authenticate(&users_file, &credentials)))
+                            .await
+                            .unwrap_or_else(|e| Err(e.to_string()));
+
+                        match user {
+                            Ok(Some((user_id, roles, two_factor))) => match start_session(&mut state, &user_id, roles, two_factor) {
+                                Ok((identifier, session_cookie)) => {
+                                    LoginThrottle::borrow_from(&state).record_success(&user_id);
+                                    let detail = match two_factor {
+                                        true => "with a second factor",
+                                        false => "with a password only",
+                                    };
+                                    AuditLog::borrow_from(&state).record(AuditEntry::new("login", Some(&user_id), Some(&ip), None, detail.to_string()));
+                                    let (csrf_token, csrf_cookie) = csrf::token_for(&state, &identifier);
+                                    let payload = AuthResponse { two_factor: Some(two_factor), ..AuthResponse::success(Some(user_id), csrf_token) };
+                                    (StatusCode::OK, payload, vec![session_cookie, csrf_cookie])
+                                }
+                                Err(e) => {
+                                    error!("cannot create the session of {user_id}: {e}");
+                                    (StatusCode::INTERNAL_SERVER_ERROR, AuthResponse::failure("cannot create the session"), Vec::new())
+                                }
+                            },
+                            Ok(None) => {
+                                record_failed_login(&state, &keys, &username, &ip);
+                                (StatusCode::UNAUTHORIZED, AuthResponse::failure("invalid username or password"), Vec::new())
+                            }
+                            Err(e) => {
+                                error!("{e}");
+                                (StatusCode::INTERNAL_SERVER_ERROR, AuthResponse::failure("cannot check the credentials"), Vec::new())
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        let response_payload = serde_json::to_string(&payload).unwrap_or_default();
+        let mut res = create_response(&state, status, APPLICATION_JSON, response_payload);
+        for cookie in cookies {
+            res.headers_mut().append(SET_COOKIE, cookie.parse().unwrap());
         }
-        Err(e) => future::err((state, e.into())),
-    });
+        if let Some(seconds) = retry_after {
+            res.headers_mut().insert(RETRY_AFTER, seconds.into());
+        }
+        Ok((state, res))
+    };
+
+    logging::timed(span, f.boxed())
+}
+
+// Username and roles of the user matching the credentials, and whether they gave a valid second factor.
+// A wrong second factor fails the login like a wrong password
+fn authenticate(users_file: &str, credentials: &Credentials) -> Result<Option<(String, Vec<Role>, bool)>, String> {
+    let store = UserStore::load(users_file)?;
+    let (user_id, roles, enrolled) = match store.authenticate(&credentials.username, &credentials.password) {
+        Some(user) => (user.username.clone(), user.roles.clone(), user.totp.is_some()),
+        None => return Ok(None),
+    };
+    // Only a consumed recovery code changes the store
+    let accepted = match (enrolled, &credentials.totp) {
+        (true, Some(code)) => match store.verify_second_factor(&user_id, code) {
+            Some(SecondFactor::Totp) => true,
+            Some(SecondFactor::RecoveryCode(hash)) => users::consume_recovery_code(users_file, &user_id, &hash)?,
+            None => false,
+        },
+        _ => return Ok(Some((user_id, roles, false))),
+    };
+    Ok(accepted.then_some((user_id, roles, true)))
+}
+
+// Audit the failure, delay the next attempts of the IP address and of the username, and audit their lockouts
+fn record_failed_login(state: &State, keys: &[String], username: &str, ip: &str) {
+    let audit_log = AuditLog::borrow_from(state);
+    audit_log.record(AuditEntry::new("login_failed", Some(username), Some(ip), None, String::new()));
+    let penalties = LoginThrottle::borrow_from(state).record_failure(keys);
+    for (key, penalty) in keys.iter().zip(penalties) {
+        if let Penalty::Lockout(duration) = penalty {
+            let detail = format!("locked for {} seconds", duration.as_secs());
+            audit_log.record(AuditEntry::new("login_lockout", Some(username), Some(ip), Some(key), detail));
+        }
+    }
+}
+
+// Store the login under a new session identifier and forget the previous one,
+// so that an identifier known before the login (eg planted by an attacker) is worthless after it.
+// Returns the new identifier with its Set-Cookie
+fn start_session(state: &mut State, user_id: &str, roles: Vec<Role>, two_factor: bool) -> Result<(String, String), String> {
+    let store = SessionStore::borrow_from(state).clone();
+    let previous_identifier = session_identifier(state);
+
+    // Without its session data, the middleware neither saves the previous session nor sends its cookie
+    let previous_session = state.try_take::<SessionData<Option<LoginData>>>();
+    if let Some(value) = previous_identifier {
+        if let Some(login_data) = previous_session.as_deref().and_then(Option::as_ref) {
+            store.untrack(&login_data.user_id, &value);
+        }
+        store.backend.drop_session(SessionIdentifier { value }).map_err(|e| format!("{e:?}"))?;
+    }
+
+    let content = bincode::serialize(&Some(LoginData::new(user_id, roles, two_factor))).map_err(|e| e.to_string())?;
+    let identifier = new_session_identifier();
+    store.backend.persist_session(identifier.clone(), &content).map_err(|e| format!("{e:?}"))?;
+    store.track(user_id, &identifier.value);
+    let cookie = store.cookie(SESSION_COOKIE, &identifier.value);
+    Ok((identifier.value, cookie))
+}
+
+// Login of the current session or API token, None for anonymous visitors
+pub fn current_login(state: &State) -> Option<&LoginData> {
+    if let Some(TokenLogin(login_data)) = TokenLogin::try_borrow_from(state) {
+        return Some(login_data)
+    }
+    SessionData::<Option<LoginData>>::try_borrow_from(state)
+        .and_then(|session| session.as_ref())
+        .filter(|login_data| login_data.connected)
+}
+
+// Identifier of the current session, as sent by the browser
+pub(crate) fn session_identifier(state: &State) -> Option<String> {
+    CookieParser::from_state(state).get(SESSION_COOKIE).map(|cookie| cookie.value().to_string())
+}
+
+// End the session: forget it on the server and clear the cookie
+pub fn disconnect_user(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let span = debug_span!("disconnect_user");
+    let _entered = span.clone().entered();
+    let store = SessionStore::borrow_from(&state).clone();
+    let identifier = session_identifier(&state);
+    let logout = AuditEntry::of_request(&state, "logout", None, String::new());
+    let (status, payload, csrf_cookie) = match state.try_take::<SessionData<Option<LoginData>>>() {
+        Some(session) => {
+            if let (Some(login_data), Some(identifier)) = (session.as_ref(), &identifier) {
+                store.untrack(&login_data.user_id, identifier);
+            }
+            if session.as_ref().is_some_and(|login_data| login_data.connected) {
+                AuditLog::borrow_from(&state).record(logout);
+            }
+            // The session middleware clears the cookie of discarded sessions
+            match session.discard(&mut state) {
+                Ok(()) => {
+                    let (csrf_token, csrf_cookie) = csrf::token_for(&state, "");
+                    (StatusCode::OK, AuthResponse::success(None, csrf_token), Some(csrf_cookie))
+                }
+                Err(e) => {
+                    error!("cannot discard the session: {e:?}");
+                    (StatusCode::INTERNAL_SERVER_ERROR, AuthResponse::failure("cannot end the session"), None)
+                }
+            }
+        }
+        None => {
+            let (csrf_token, csrf_cookie) = csrf::token_for(&state, "");
+            (StatusCode::OK, AuthResponse::success(None, csrf_token), Some(csrf_cookie))
+        }
+    };
+
+    let response_payload = serde_json::to_string(&payload).unwrap_or_default();
+    let mut res = create_response(&state, status, APPLICATION_JSON, response_payload);
+    if let Some(cookie) = csrf_cookie {
+        res.headers_mut().append(SET_COOKIE, cookie.parse().unwrap());
+    }
+    logging::timed(span, future::ok((state, res)).boxed())
+}
 
-    f.boxed()
+// :username of /api/admin/users/:username/sessions
+#[derive(Deserialize, StateData)]
+pub struct UserPath {
+    username: String,
 }
 
-// Check if credentials are valid
-// We have some default login credentials
-fn is_credential_valid(username: &str, password: &str) -> bool {
-    return if username == "Spock" && password == "enigma42" { true }
-    else { false }
+// gotham_derive 0.7 derives the extender of gotham 0.7, a missing username is answered with a plain 400
+impl StaticResponseExtender for UserPath {
+    type ResBody = Body;
+
+    fn extend(_state: &mut State, _response: &mut Response<Body>) {}
+}
+
+#[derive(Serialize)]
+struct RevokeResponse {
+    success: bool,
+    revoked: usize,
+}
+
+// Log a user out of every device, eg after a password change: DELETE /api/admin/users/<username>/sessions
+pub fn revoke_sessions_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let username = UserPath::borrow_from(&state).username.clone();
+    let span = debug_span!("revoke_sessions_handler", username = %username);
+    let _entered = span.clone().entered();
+
+    // Only admins reach this handler, see the admin scope in build_router
+    let admin = current_login(&state).map(|login_data| login_data.user_id.clone()).unwrap_or_default();
+
+    let (status, response_payload) = match SessionStore::borrow_from(&state).revoke_user_sessions(&username) {
+        Ok(revoked) => {
+            info!("{admin} revoked {revoked} sessions of {username}");
+            AuditLog::borrow_from(&state).record(AuditEntry::of_request(&state, "sessions_revoked", Some(&username), format!("{revoked} sessions")));
+            (StatusCode::OK, serde_json::to_string(&RevokeResponse { success: true, revoked }))
+        }
+        Err(e) => {
+            error!("cannot revoke the sessions of {username}: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, serde_json::to_string(&AuthResponse::failure("cannot revoke the sessions")))
+        }
+    };
+
+    let res = create_response(&state, status, APPLICATION_JSON, response_payload.unwrap_or_default());
+    logging::timed(span, future::ok((state, res)).boxed())
+}
+
+// 64 random bytes, as long as the identifiers of the session middleware
+fn new_session_identifier() -> SessionIdentifier {
+    let mut bytes = [0u8; 64];
+    OsRng.fill_bytes(&mut bytes);
+    SessionIdentifier { value: bytes.iter().map(|byte| format!("{byte:02x}")).collect() }
 }
 
 
+// Whether the session is logged in, and for how many seconds it will stay so without activity
+#[derive(Serialize)]
+struct SessionStatus {
+    connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<Role>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    two_factor: Option<bool>,
+}
+
 pub fn is_user_connected(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let span = debug_span!("is_user_connected");
     let f = body::to_bytes(Body::take_from(&mut state)).then(|full_body| match full_body {
-        Ok(valid_body) => {
+        Ok(_valid_body) => {
 
-            let login_data: &mut Option<LoginData> = SessionData::<Option<LoginData>>::borrow_mut_from(&mut state);
-            let is_user_connected = match login_data {
-                Some(ref login_data) => login_data.connected,
-                None => false,
+            // Expired sessions have already been logged out by the SessionExpiryMiddleware
+            let limits = SessionLimits::borrow_from(&state);
+            let login_data: &Option<LoginData> = SessionData::<Option<LoginData>>::borrow_from(&state);
+            let status = match login_data {
+                Some(login_data) if login_data.connected => SessionStatus {
+                    connected: true,
+                    user_id: Some(login_data.user_id.clone()),
+                    expires_in: login_data.remaining_lifetime(limits),
+                    roles: login_data.roles.clone(),
+                    two_factor: Some(login_data.two_factor),
+                },
+                _ => SessionStatus { connected: false, user_id: None, expires_in: None, roles: Vec::new(), two_factor: None },
             };
 
-            if is_user_connected {
-                // User is connected
-                let response_payload = format!("user is connected");
-                let mut res = create_response(&mut state, StatusCode::OK, TEXT_HTML, response_payload);
-                future::ok((state, res))
-            } else {
-                let response_payload = format!("user is NOT connected");
-                let mut res = create_response(&mut state, StatusCode::OK, TEXT_HTML, response_payload);
-                future::ok((state, res))
-            }
+            let response_payload = serde_json::to_string(&status).unwrap_or_default();
+            let res = create_response(&state, StatusCode::OK, APPLICATION_JSON, response_payload);
+            future::ok((state, res))
 
         }
         Err(e) => future::err((state, e.into())),
     });
 
-    f.boxed()
+    logging::timed(span, f.boxed())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use gotham::hyper::header::COOKIE;
+    use gotham::hyper::Request;
+    use gotham::middleware::session::MemoryBackend;
+    use crate::session_backend::FileBackend;
 
-// OriginDomain struct that will keep in shared memory the origin domain access for header creation.
-// This is the middlewares structure
-// Todo: this will be merged with other branches' middlewares structures
-#[derive(Clone, StateData)]
-pub struct OriginDomain {
-    pub origin_domain: String,
-}
+    // Session stored by the login handler
+    fn log_in(store: &SessionStore, user_id: &str) -> (SessionIdentifier, Vec<u8>) {
+        let content = bincode::serialize(&Some(LoginData::new(user_id, vec![Role::Member], false))).unwrap();
+        let identifier = new_session_identifier();
+        store.backend.persist_session(identifier.clone(), &content).unwrap();
+        (identifier, content)
+    }
 
-/// Counter implementation.
-impl OriginDomain {
-    /// Creates a new origin domain to allow cross domain sharing
-    pub(crate) fn new(origin: String) -> Self {
-        Self {
-            origin_domain: origin,
-        }
+    #[test]
+    fn revokes_the_sessions_of_every_instance() {
+        let dir = std::env::temp_dir().join(format!("orailix-sessions-{}", std::process::id()));
+        let instance = |dir: &std::path::Path| {
+            let backend = FileBackend::new(&dir.to_string_lossy(), Duration::from_secs(3600)).unwrap();
+            SessionStore::new(SessionBackend::File(backend), false, SameSite::Lax)
+        };
+        let (first, second) = (instance(&dir), instance(&dir));
+
+        // Logged in on the first instance, or before a restart, and never tracked by the second one
+        let (bob, bob_content) = log_in(&first, "bob");
+        log_in(&first, "carol");
+        assert_eq!(second.revoke_user_sessions("bob"), Ok(1));
+        let remaining: Vec<LoginData> = second.backend.sessions().iter()
+            .filter_map(|(_, content)| bincode::deserialize::<Option<LoginData>>(content).unwrap())
+            .collect();
+        assert_eq!(remaining.iter().map(|login_data| login_data.user_id.as_str()).collect::<Vec<_>>(), ["carol"]);
+        assert!(!first.is_revoked(&remaining[0]));
+
+        // A request in progress on the first instance writes the revoked session back
+        first.backend.persist_session(bob, &bob_content).unwrap();
+        let written_back: Option<LoginData> = bincode::deserialize(&bob_content).unwrap();
+        assert!(first.is_revoked(written_back.as_ref().unwrap()));
+        assert!(!first.is_revoked(&LoginData::new("bob", vec![Role::Member], false)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
-}
 
-// Get the domain of the website passed as a command line argument during initialization
-pub fn get_domain_origin(state: &State) -> String {
-    let origin_domain = OriginDomain::borrow_from(&state).origin_domain.clone();
-    return origin_domain
-}
+    fn read(store: &SessionStore, identifier: &str) -> Option<Vec<u8>> {
+        store.backend.read_session(SessionIdentifier { value: identifier.to_string() }).now_or_never().unwrap().unwrap()
+    }
 
-// Format the header according to our specification
-pub fn header_formatting(mut res: Response<Body>, state: &State) -> Response<Body> {
+    #[test]
+    fn changes_the_session_identifier_on_login() {
+        let store = SessionStore::new(SessionBackend::Memory(MemoryBackend::new(Duration::from_secs(3600)), Arc::default()), true, SameSite::Strict);
+        // Identifier planted before the login
+        let previous = "a".repeat(86);
+        store.backend.persist_session(SessionIdentifier { value: previous.clone() }, &[0]).unwrap();
 
-    let utc = Utc::now();
+        let request = Request::builder().header(COOKIE, format!("{SESSION_COOKIE}={previous}")).body(Body::empty()).unwrap();
+        let mut state = State::from_request(request, "127.0.0.1:40000".parse().unwrap());
+        state.put(store.clone());
+        let (identifier, cookie) = start_session(&mut state, "bob", vec![Role::Editor], true).unwrap();
 
-    let headers = res.headers_mut();
-    headers.insert("Strict-Transport-Security", "max-age=63072000".parse().unwrap());
-    headers.insert("X-Frame-Options", "SAMEORIGIN".parse().unwrap());
-    headers.insert("X-Content-Type-Options", "nosniff".parse().unwrap());
-    headers.insert("Access-Control-Allow-Origin", get_domain_origin(&state).parse().unwrap());
-    headers.insert("Vary", "Origin".parse().unwrap());
-    headers.insert("Permissions-Policy", "accelerometer=(), ambient-light-sensor=(), autoplay=(), battery=(), camera=(), cross-origin-isolated=(), display-capture=(), document-domain=(), encrypted-media=(), execution-while-not-rendered=(), execution-while-out-of-viewport=(), fullscreen=(), geolocation=(), gyroscope=(), keyboard-map=(), magnetometer=(), microphone=(), midi=(), navigation-override=(), payment=(), picture-in-picture=(), publickey-credentials-get=(), screen-wake-lock=(), sync-xhr=(), usb=(), web-share=(), xr-spatial-tracking=()".parse().unwrap());
-    headers.insert("Date", format!("{:?}", utc).parse().unwrap());
-    return res
-}
\ No newline at end of file
+        assert_ne!(identifier, previous);
+        assert_eq!(cookie, format!("{SESSION_COOKIE}={identifier}; Secure; HttpOnly; SameSite=Strict; Path=/"));
+        assert_eq!(read(&store, &previous), None);
+        let login_data: Option<LoginData> = bincode::deserialize(&read(&store, &identifier).unwrap()).unwrap();
+        let login_data = login_data.unwrap();
+        assert_eq!((login_data.user_id.as_str(), login_data.roles, login_data.two_factor), ("bob", vec![Role::Editor], true));
+    }
+
+    #[test]
+    fn sessions_expire_when_idle_or_too_old() {
+        let limits = SessionLimits { session_lifetime: 3600, idle_timeout: 60 };
+        let ago = |seconds: i64| (Utc::now() - chrono::Duration::seconds(seconds)).to_rfc3339();
+        let session = |logged_in: i64, interaction: i64| LoginData {
+            logged_in_at: ago(logged_in),
+            last_interaction: ago(interaction),
+            ..LoginData::new("bob", vec![Role::Member], false)
+        };
+        assert!(session(100, 10).remaining_lifetime(&limits).is_some_and(|remaining| (49..=50).contains(&remaining)));
+        assert!(session(3590, 0).remaining_lifetime(&limits).is_some_and(|remaining| (9..=10).contains(&remaining)));
+        assert!(session(100, 61).remaining_lifetime(&limits).is_none());
+        assert!(session(3601, 0).remaining_lifetime(&limits).is_none());
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use gotham_derive::StateData;
+use crate::config::LoginConfig;
+
+// Beyond this size, the keys whose last failure is the oldest are forgotten
+const MAX_TRACKED_KEYS: usize = 10000;
+
+// Failed logins of one IP address or one username
+#[derive(Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+    // Position of the key in Table::by_recency
+    recency: u64,
+}
+
+// The attempts of every key, with the keys ordered by their last failure so that the oldest is evicted first
+#[derive(Default)]
+struct Table {
+    attempts: HashMap<String, Attempts>,
+    by_recency: BTreeMap<u64, String>,
+    next_recency: u64,
+}
+
+impl Table {
+    // Move the key to the most recent end, evicting the oldest keys past MAX_TRACKED_KEYS
+    fn touch(&mut self, key: &str, now: Instant) -> &mut Attempts {
+        let recency = self.next_recency;
+        self.next_recency += 1;
+        match self.attempts.get_mut(key) {
+            Some(attempt) => {
+                self.by_recency.remove(&attempt.recency);
+                attempt.recency = recency;
+            }
+            None => {
+                while self.attempts.len() >= MAX_TRACKED_KEYS {
+                    match self.by_recency.pop_first() {
+                        Some((_, oldest)) => self.attempts.remove(&oldest),
+                        None => break,
+                    };
+                }
+                self.attempts.insert(key.to_string(), Attempts { failures: 0, last_failure: now, blocked_until: None, recency });
+            }
+        }
+        self.by_recency.insert(recency, key.to_string());
+        self.attempts.get_mut(key).expect("the key was just inserted")
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(attempt) = self.attempts.remove(key) {
+            self.by_recency.remove(&attempt.recency);
+        }
+    }
+}
+
+// What a failed attempt led to
+#[derive(Debug, PartialEq)]
+pub enum Penalty {
+    // Still within the free attempts
+    None,
+    // The next attempt must wait, the delay doubles with every failure
+    Backoff(Duration),
+    // Too many failures, locked for the configured duration
+    Lockout(Duration),
+}
+
+// In-process tracking of the failed logins per IP address and per username
+#[derive(Clone, StateData)]
+pub struct LoginThrottle {
+    config: LoginConfig,
+    attempts: Arc<Mutex<Table>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: LoginConfig) -> Self {
+        Self { config, attempts: Arc::default() }
+    }
+
+    pub fn ip_key(ip: &str) -> String {
+        format!("ip:{ip}")
+    }
+
+    pub fn username_key(username: &str) -> String {
+        format!("user:{}", username.to_lowercase())
+    }
+
+    // Seconds to wait before the next attempt, None if the attempt is allowed
+    pub fn retry_after(&self, keys: &[String]) -> Option<u64> {
+        self.retry_after_at(keys, Instant::now())
+    }
+
+    // Penalty of every key, in the same order
+    pub fn record_failure(&self, keys: &[String]) -> Vec<Penalty> {
+        self.record_failure_at(keys, Instant::now())
+    }
+
+    // A successful login clears the username, the IP address keeps its record so that a valid
+    // account cannot be used to keep guessing the others
+    pub fn record_success(&self, username: &str) {
+        if let Ok(mut attempts) = self.attempts.lock() {
+            attempts.remove(&Self::username_key(username));
+        }
+    }
+
+    fn retry_after_at(&self, keys: &[String], now: Instant) -> Option<u64> {
+        let attempts = self.attempts.lock().ok()?;
+        keys.iter()
+            .filter_map(|key| attempts.attempts.get(key)?.blocked_until)
+            .filter(|blocked_until| *blocked_until > now)
+            .map(|blocked_until| (blocked_until - now).as_secs_f64().ceil() as u64)
+            .max()
+    }
+
+    fn record_failure_at(&self, keys: &[String], now: Instant) -> Vec<Penalty> {
+        let mut attempts = match self.attempts.lock() {
+            Ok(attempts) => attempts,
+            Err(_) => return Vec::new(),
+        };
+        let window = Duration::from_secs(self.config.window);
+        keys.iter()
+            .map(|key| {
+                let attempt = attempts.touch(key, now);
+                // Failures are forgotten after a quiet window
+                if now.duration_since(attempt.last_failure) >= window {
+                    attempt.failures = 0;
+                }
+                attempt.failures += 1;
+                attempt.last_failure = now;
+
+                let penalty = self.penalty(attempt.failures);
+                attempt.blocked_until = match penalty {
+                    Penalty::None => None,
+                    Penalty::Backoff(delay) | Penalty::Lockout(delay) => Some(now + delay),
+                };
+                penalty
+            })
+            .collect()
+    }
+
+    fn penalty(&self, failures: u32) -> Penalty {
+        if failures >= self.config.lockout_threshold {
+            return Penalty::Lockout(Duration::from_secs(self.config.lockout_duration))
+        }
+        match failures.checked_sub(self.config.free_attempts) {
+            None | Some(0) => Penalty::None,
+            Some(extra) => {
+                let delay = self.config.base_delay.saturating_mul(2u64.saturating_pow(extra - 1));
+                Penalty::Backoff(Duration::from_secs(delay.min(self.config.max_delay)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(LoginConfig {
+            free_attempts: 3,
+            base_delay: 2,
+            max_delay: 20,
+            lockout_threshold: 8,
+            lockout_duration: 600,
+            window: 900,
+        })
+    }
+
+    fn keys() -> Vec<String> {
+        vec![LoginThrottle::ip_key("192.0.2.1"), LoginThrottle::username_key("alice")]
+    }
+
+    #[test]
+    fn free_attempts_are_not_delayed() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(throttle.record_failure_at(&keys(), now), vec![Penalty::None, Penalty::None]);
+        }
+        assert_eq!(throttle.retry_after_at(&keys(), now), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let throttle = throttle();
+        let key = vec![LoginThrottle::username_key("alice")];
+        let now = Instant::now();
+        let penalties: Vec<Penalty> = (0..7).flat_map(|_| throttle.record_failure_at(&key, now)).collect();
+        assert_eq!(penalties[3..], [
+            Penalty::Backoff(Duration::from_secs(2)),
+            Penalty::Backoff(Duration::from_secs(4)),
+            Penalty::Backoff(Duration::from_secs(8)),
+            Penalty::Backoff(Duration::from_secs(16)),
+        ]);
+        assert_eq!(throttle.record_failure_at(&key, now), vec![Penalty::Lockout(Duration::from_secs(600))]);
+
+        let throttle = LoginThrottle::new(LoginConfig { lockout_threshold: 100, ..throttle.config });
+        let penalties: Vec<Penalty> = (0..9).flat_map(|_| throttle.record_failure_at(&key, now)).collect();
+        assert_eq!(penalties[8], Penalty::Backoff(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn blocked_keys_must_wait() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for _ in 0..4 {
+            throttle.record_failure_at(&keys(), now);
+        }
+        assert_eq!(throttle.retry_after_at(&keys(), now), Some(2));
+        assert_eq!(throttle.retry_after_at(&keys(), now + Duration::from_millis(1500)), Some(1));
+        assert_eq!(throttle.retry_after_at(&keys(), now + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn lockout_lasts_its_duration() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let penalties: Vec<Penalty> = (0..8).flat_map(|_| throttle.record_failure_at(&keys(), now)).collect();
+        assert_eq!(penalties[14..], [Penalty::Lockout(Duration::from_secs(600)), Penalty::Lockout(Duration::from_secs(600))]);
+        assert_eq!(throttle.retry_after_at(&keys(), now + Duration::from_secs(599)), Some(1));
+        assert_eq!(throttle.retry_after_at(&keys(), now + Duration::from_secs(600)), None);
+    }
+
+    #[test]
+    fn ip_and_username_are_tracked_separately() {
+        let throttle = throttle();
+        let now = Instant::now();
+        // One IP address guessing many usernames
+        for username in ["alice", "bob", "carol", "dave"] {
+            throttle.record_failure_at(&[LoginThrottle::ip_key("192.0.2.1"), LoginThrottle::username_key(username)], now);
+        }
+        assert_eq!(throttle.retry_after_at(&[LoginThrottle::ip_key("192.0.2.1")], now), Some(2));
+        assert_eq!(throttle.retry_after_at(&[LoginThrottle::ip_key("192.0.2.2"), LoginThrottle::username_key("erin")], now), None);
+        assert_eq!(throttle.retry_after_at(&[LoginThrottle::ip_key("192.0.2.2"), LoginThrottle::username_key("alice")], now), None);
+    }
+
+    #[test]
+    fn usernames_are_case_insensitive() {
+        assert_eq!(LoginThrottle::username_key("Alice"), LoginThrottle::username_key("alice"));
+    }
+
+    #[test]
+    fn success_clears_the_username_only() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for _ in 0..4 {
+            throttle.record_failure_at(&keys(), now);
+        }
+        throttle.record_success("alice");
+        assert_eq!(throttle.retry_after_at(&[LoginThrottle::username_key("alice")], now), None);
+        assert_eq!(throttle.retry_after_at(&[LoginThrottle::ip_key("192.0.2.1")], now), Some(2));
+    }
+
+    #[test]
+    fn the_oldest_keys_are_evicted_past_the_limit() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for _ in 0..4 {
+            throttle.record_failure_at(&keys(), now);
+        }
+        for i in 0..MAX_TRACKED_KEYS {
+            throttle.record_failure_at(&[LoginThrottle::ip_key(&format!("10.0.{}.{}", i / 256, i % 256))], now);
+        }
+        assert_eq!(throttle.attempts.lock().unwrap().attempts.len(), MAX_TRACKED_KEYS);
+        assert_eq!(throttle.attempts.lock().unwrap().by_recency.len(), MAX_TRACKED_KEYS);
+        // Both keys of the first client were the oldest
+        assert_eq!(throttle.retry_after_at(&keys(), now), None);
+        assert!(throttle.attempts.lock().unwrap().attempts.contains_key(&LoginThrottle::ip_key("10.0.0.2")));
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_the_window() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for _ in 0..3 {
+            throttle.record_failure_at(&keys(), now);
+        }
+        let later = now + Duration::from_secs(900);
+        assert_eq!(throttle.record_failure_at(&keys(), later), vec![Penalty::None, Penalty::None]);
+    }
+}
@@ -0,0 +1,129 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::Sha1;
+
+// RFC 6238 time-based one-time passwords with the parameters every authenticator app supports:
+// HMAC-SHA1, 6 digits, 30 seconds steps
+const DIGITS: u32 = 6;
+const STEP: u64 = 30;
+// Codes of the previous and next steps are accepted too, for clocks slightly off
+const SKEW: u64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+
+// Random shared secret, in base32 as typed into the authenticator apps
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+// RFC 4226 HOTP value of a counter
+pub(crate) fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+// Time step of the code if it is valid at unix_time, so that the caller can refuse a code used twice
+pub fn verify(secret: &str, code: &str, unix_time: u64) -> Option<u64> {
+    let secret = BASE32_NOPAD.decode(secret.trim_end_matches('=').as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = unix_time / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| hotp(&secret, *step) == code)
+}
+
+// Whether a second factor looks like a TOTP code rather than a recovery code
+pub fn is_code(code: &str) -> bool {
+    code.trim().chars().all(|c| c.is_ascii_digit())
+}
+
+// Key URI understood by the authenticator apps, https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = encode_component(issuer);
+    format!("otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}", encode_component(account))
+}
+
+// The URI as a QR code drawn with Unicode blocks, to be scanned from the terminal
+pub fn qr_code(uri: &str) -> Result<String, String> {
+    let code = QrCode::new(uri.as_bytes()).map_err(|e| format!("cannot build the QR code: {e}"))?;
+    Ok(code.render::<Dense1x2>().dark_color(Dense1x2::Light).light_color(Dense1x2::Dark).build())
+}
+
+// Single use codes to log in without the authenticator app, eg abcd-efgh-ijkl
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 8];
+            OsRng.fill_bytes(&mut bytes);
+            let code = BASE32_NOPAD.encode(&bytes).to_lowercase();
+            format!("{}-{}-{}", &code[0..4], &code[4..8], &code[8..12])
+        })
+        .collect()
+}
+
+// Recovery codes are compared without their dashes and case, as people retype them
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(char::is_ascii_alphanumeric).collect::<String>().to_lowercase()
+}
+
+fn encode_component(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors of RFC 6238 appendix B for SHA1, truncated to 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_the_rfc_test_vectors() {
+        for (time, code) in [(59, 287082), (1111111109, 81804), (1111111111, 50471), (1234567890, 5924), (2000000000, 279037), (20000000000, 353130)] {
+            assert_eq!(hotp(RFC_SECRET, time / STEP), code);
+        }
+    }
+
+    #[test]
+    fn verifies_codes_around_the_current_step() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        assert_eq!(verify(&secret, "287082", 59), Some(1));
+        assert_eq!(verify(&secret, "287082", 89), Some(1));
+        assert_eq!(verify(&secret, "287082", 120), None);
+        assert_eq!(verify(&secret, "081804", 1111111109), Some(1111111109 / STEP));
+        assert_eq!(verify(&secret, "81804", 1111111109), None);
+        assert_eq!(verify(&secret, "28708a", 59), None);
+    }
+
+    #[test]
+    fn builds_the_key_uri() {
+        assert_eq!(
+            otpauth_uri("ORAILIX", "jane doe", "JBSWY3DPEHPK3PXP"),
+            "otpauth://totp/ORAILIX:jane%20doe?secret=JBSWY3DPEHPK3PXP&issuer=ORAILIX&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn recovery_codes_are_normalized() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(normalize_recovery_code(&codes[0].to_uppercase()), codes[0].replace('-', ""));
+    }
+}
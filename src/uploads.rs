@@ -0,0 +1,349 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use data_encoding::HEXLOWER;
+use futures_util::FutureExt;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::CONTENT_TYPE;
+use gotham::hyper::{Body, HeaderMap, StatusCode, Uri};
+use gotham::state::{FromState, State};
+use img_parts::riff::RiffContent;
+use img_parts::{jpeg, webp, Bytes, DynImage, ImageEXIF};
+use mime::APPLICATION_JSON;
+use multer::{Constraints, Multipart, SizeLimit};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::admin_articles::{article_location, ARTICLE_WRITES};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::config::{Config, GitConfig, UploadsConfig};
+use crate::content_git::{self, Author};
+use crate::session_management::current_login;
+use crate::validate::NEWS_PATH;
+use tracing::{error, info};
+
+const WEBSITE_PATH: &str = "orailix.com";
+pub const MEDIA_ROUTE: &str = "/api/admin/media";
+
+// Types accepted by the upload endpoints, recognized from the content rather than from the name
+#[derive(Clone, Copy, PartialEq)]
+enum MediaType {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Pdf,
+}
+
+impl MediaType {
+    // Magic bytes at the start of the file
+    fn sniff(content: &[u8]) -> Option<Self> {
+        match content {
+            [0xFF, 0xD8, 0xFF, ..] => Some(MediaType::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(MediaType::Png),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(MediaType::Gif),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(MediaType::WebP),
+            [b'%', b'P', b'D', b'F', b'-', ..] => Some(MediaType::Pdf),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            MediaType::Jpeg => "jpg",
+            MediaType::Png => "png",
+            MediaType::Gif => "gif",
+            MediaType::WebP => "webp",
+            MediaType::Pdf => "pdf",
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            MediaType::Jpeg => "image/jpeg",
+            MediaType::Png => "image/png",
+            MediaType::Gif => "image/gif",
+            MediaType::WebP => "image/webp",
+            MediaType::Pdf => "application/pdf",
+        }
+    }
+
+    // Whether a file name has the extension of the type, a PNG named .jpg is refused
+    fn matches_name(self, file_name: &str) -> bool {
+        let extension = Path::new(file_name).extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+        extension == self.extension() || (self == MediaType::Jpeg && extension == "jpeg")
+    }
+}
+
+#[derive(Serialize)]
+struct UploadedFile {
+    // Name in the folder, eg to use as the picture of the manifest
+    name: String,
+    // Path on the website and public URL
+    path: String,
+    url: String,
+    mime_type: &'static str,
+    size: usize,
+    // The same content was already there, nothing was written
+    duplicate: bool,
+}
+
+#[derive(Default, Serialize)]
+struct UploadResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    files: Vec<UploadedFile>,
+    // Commit of the new files on the content branch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+type UploadError = (StatusCode, String);
+
+// Folder receiving the files, in the website folder:
+//  POST /api/admin/media                                 the media library, uploads.media_folder
+//  POST /api/admin/articles/<category>/<folder>/media    the folder of an article
+fn upload_folder(uri_path: &str, config: &UploadsConfig) -> Result<String, UploadError> {
+    if uri_path.trim_end_matches('/') == MEDIA_ROUTE {
+        return Ok(config.media_folder.trim_matches('/').to_string())
+    }
+    match article_location(uri_path) {
+        Some((category, Some(folder), Some(action))) if action == "media" => {
+            let site_folder = format!("news/{category}/{folder}");
+            match Path::new(NEWS_PATH).join(&category).join(&folder).is_dir() {
+                true => Ok(site_folder),
+                false => Err((StatusCode::NOT_FOUND, format!("unknown article '{category}/{folder}'"))),
+            }
+        }
+        _ => Err((StatusCode::NOT_FOUND, format!("{uri_path} is not an upload folder"))),
+    }
+}
+
+// Check a file against the allowlist and remove its EXIF and XMP metadata (location, camera, ...).
+// Returns its type and the content to store
+fn prepare(file_name: &str, content: Bytes, allowed_types: &[String]) -> Result<(MediaType, Bytes), UploadError> {
+    let media_type = MediaType::sniff(&content)
+        .filter(|media_type| allowed_types.iter().any(|allowed| allowed == media_type.extension()))
+        .ok_or((StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("{file_name}: unsupported file type, expected {}", allowed_types.join(", "))))?;
+    if !media_type.matches_name(file_name) {
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("{file_name}: the content is a {} file", media_type.extension())))
+    }
+
+    let content = match media_type {
+        MediaType::Jpeg | MediaType::Png | MediaType::WebP => {
+            let mut image = DynImage::from_bytes(content)
+                .ok()
+                .flatten()
+                .ok_or((StatusCode::UNPROCESSABLE_ENTITY, format!("{file_name}: corrupted picture")))?;
+            strip_metadata(&mut image);
+            image.encoder().bytes()
+        }
+        MediaType::Gif | MediaType::Pdf => content,
+    };
+    Ok((media_type, content))
+}
+
+// XMP packets, in APP1 segments for JPEG, iTXt chunks for PNG and an XMP chunk for WebP, repeat the EXIF fields GPS included
+const JPEG_XMP_PREFIXES: [&[u8]; 2] = [b"http://ns.adobe.com/xap/1.0/\0", b"http://ns.adobe.com/xmp/extension/\0"];
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+// Flags of the VP8X chunk announcing the EXIF and XMP chunks
+const VP8X_METADATA_FLAGS: u8 = 0x08 | 0x04;
+
+fn strip_metadata(image: &mut DynImage) {
+    match image {
+        DynImage::Jpeg(jpeg) => jpeg.segments_mut().retain(|segment| {
+            segment.marker() != jpeg::markers::APP1 || !JPEG_XMP_PREFIXES.iter().any(|prefix| segment.contents().starts_with(prefix))
+        }),
+        DynImage::Png(png) => png.chunks_mut().retain(|chunk| {
+            !matches!(&chunk.kind(), b"iTXt" | b"tEXt" | b"zTXt") || !chunk.contents().starts_with(PNG_XMP_KEYWORD)
+        }),
+        DynImage::WebP(webp) => {
+            webp.remove_chunks_by_id(webp::CHUNK_XMP);
+            for chunk in webp.chunks_mut().iter_mut().filter(|chunk| chunk.id() == webp::CHUNK_VP8X) {
+                if let RiffContent::Data(data) = chunk.content_mut() {
+                    if let Some(flags) = data.first() {
+                        let mut cleared = data.to_vec();
+                        cleared[0] = flags & !VP8X_METADATA_FLAGS;
+                        *data = Bytes::from(cleared);
+                    }
+                }
+            }
+        }
+    }
+    image.set_exif(None);
+}
+
+// Files are named after their content, uploading the same file twice keeps a single copy
+fn store(site_folder: &str, media_type: MediaType, content: &[u8], public_origin: &str) -> Result<UploadedFile, UploadError> {
+    let hash = Sha256::digest(content);
+    let name = format!("{}.{}", HEXLOWER.encode(&hash[..16]), media_type.extension());
+    let folder = Path::new(WEBSITE_PATH).join(site_folder);
+    let destination = folder.join(&name);
+
+    let duplicate = destination.exists();
+    if !duplicate {
+        write_atomically(&folder, &destination, content)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("cannot write {}: {e}", destination.display())))?;
+    }
+    let path = format!("/{site_folder}/{name}");
+    Ok(UploadedFile { name, url: format!("{public_origin}{path}"), path, mime_type: media_type.mime(), size: content.len(), duplicate })
+}
+
+// Commit the new files on the content branch, duplicates are already there
+fn commit_files(git_config: &GitConfig, author: &Author, files: &[UploadedFile]) -> Option<String> {
+    let paths = files.iter()
+        .filter(|file| !file.duplicate)
+        .map(|file| Path::new(WEBSITE_PATH).join(file.path.trim_start_matches('/')))
+        .collect::<Vec<PathBuf>>();
+    if paths.is_empty() {
+        return None
+    }
+    let names = files.iter().filter(|file| !file.duplicate).map(|file| file.path.trim_start_matches('/')).collect::<Vec<&str>>();
+    match content_git::commit(git_config, &paths, author, &format!("Upload {}", names.join(", "))) {
+        Ok(revision) => revision,
+        Err(e) => {
+            error!("cannot commit the uploaded files: {e}");
+            None
+        }
+    }
+}
+
+// Write to a temporary file first, so that visitors never download a partial file
+fn write_atomically(folder: &Path, destination: &Path, content: &[u8]) -> std::io::Result<()> {
+    static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+    fs::create_dir_all(folder)?;
+    let temporary = destination.with_extension(format!("{}.tmp", TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, destination)
+}
+
+// Every file of a multipart/form-data body, refused as a whole if one of them is not acceptable
+async fn read_files(body: Body, content_type: &str, config: &UploadsConfig) -> Result<Vec<(String, Bytes)>, UploadError> {
+    let boundary = multer::parse_boundary(content_type).map_err(|e| (StatusCode::BAD_REQUEST, format!("expected a multipart/form-data body: {e}")))?;
+    let limits = SizeLimit::new().whole_stream(config.max_request_size).per_field(config.max_file_size);
+    let mut multipart = Multipart::with_constraints(body, boundary, Constraints::new().size_limit(limits));
+
+    let too_large = |e: multer::Error| match e {
+        multer::Error::FieldSizeExceeded { .. } | multer::Error::StreamSizeExceeded { .. } => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()),
+        e => (StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let mut files = Vec::new();
+    while let Some(field) = multipart.next_field().await.map_err(too_large)? {
+        // Text fields are ignored
+        let file_name = match field.file_name() {
+            Some(file_name) => file_name.to_string(),
+            None => continue,
+        };
+        files.push((file_name, field.bytes().await.map_err(too_large)?));
+    }
+    match files.is_empty() {
+        true => Err((StatusCode::BAD_REQUEST, "no file in the request".to_string())),
+        false => Ok(files),
+    }
+}
+
+// Upload pictures and PDFs to the media library or to an article, answering with their public URL
+pub fn upload_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let f = async move {
+        let uri_path = Uri::borrow_from(&state).path().to_string();
+        let content_type = HeaderMap::borrow_from(&state).get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let config = Config::borrow_from(&state);
+        let (uploads, public_origin) = (config.uploads.clone(), config.site.public_origin.clone());
+        let (git_config, users_file) = (config.git.clone(), config.auth.users_file.clone());
+        let editor = current_login(&state).map(|login_data| login_data.user_id.clone()).unwrap_or_default();
+        let body = Body::take_from(&mut state);
+
+        let result = match upload_folder(&uri_path, &uploads) {
+            Ok(site_folder) => match read_files(body, &content_type, &uploads).await {
+                // Decoding, writing and committing the files block
+                Ok(files) => {
+                    let author_id = editor.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let files = files.into_iter()
+                            .map(|(file_name, content)| prepare(&file_name, content, &uploads.allowed_types))
+                            .collect::<Result<Vec<(MediaType, Bytes)>, UploadError>>()?;
+                        // Article folders are also replaced as a whole by the admin API
+                        let _guard = ARTICLE_WRITES.lock().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+                        let files = files.into_iter()
+                            .map(|(media_type, content)| store(&site_folder, media_type, &content, &public_origin))
+                            .collect::<Result<Vec<UploadedFile>, UploadError>>()?;
+                        let revision = match git_config.enabled {
+                            true => commit_files(&git_config, &Author::of(&users_file, &author_id, &git_config), &files),
+                            false => None,
+                        };
+                        Ok((files, revision))
+                    })
+                    .await
+                    .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())))
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+
+        let (status, response) = match result {
+            Ok((files, revision)) => {
+                let detail = revision.as_ref().map(|revision| format!(", commit {revision}")).unwrap_or_default();
+                for file in &files {
+                    info!("{editor} uploaded {}", file.path);
+                    let entry = AuditEntry::of_request(&state, "upload", Some(&file.path), format!("{}, {} bytes{detail}", file.mime_type, file.size));
+                    AuditLog::borrow_from(&state).record(entry);
+                }
+                (StatusCode::CREATED, UploadResponse { success: true, files, revision, error: None })
+            }
+            Err((status, error)) => (status, UploadResponse { error: Some(error), ..UploadResponse::default() }),
+        };
+        let response_payload = serde_json::to_string(&response).unwrap_or_default();
+        let res = create_response(&state, status, APPLICATION_JSON, response_payload);
+        Ok((state, res))
+    };
+    f.boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use img_parts::riff::RiffChunk;
+
+    const GPS_EXIF: &[u8] = b"MM\0*\0\0\0\x08GPSLatitude 48/1 42/1 30/1 N GPSLongitude 2/1 12/1 40/1 E";
+    const GPS_XMP: &[u8] = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:Description exif:GPSLatitude="48,42.5N" exif:GPSLongitude="2,12.67E"/></x:xmpmeta>"#;
+
+    // A 2x2 picture carrying the location of where it was taken in both EXIF and XMP
+    fn gps_tagged(format: ImageFormat) -> Bytes {
+        let mut encoded = std::io::Cursor::new(Vec::new());
+        RgbImage::new(2, 2).write_to(&mut encoded, format).unwrap();
+        let mut image = DynImage::from_bytes(encoded.into_inner().into()).unwrap().unwrap();
+        image.set_exif(Some(Bytes::from_static(GPS_EXIF)));
+        let xmp = |prefix: &[u8]| Bytes::from([prefix, GPS_XMP].concat());
+        match &mut image {
+            DynImage::Jpeg(jpeg) => jpeg.segments_mut().insert(1, jpeg::JpegSegment::new_with_contents(jpeg::markers::APP1, xmp(JPEG_XMP_PREFIXES[0]))),
+            DynImage::Png(png) => png.chunks_mut().insert(1, img_parts::png::PngChunk::new(*b"iTXt", xmp(b"XML:com.adobe.xmp\0\0\0\0\0"))),
+            DynImage::WebP(webp) => webp.chunks_mut().push(RiffChunk::new(webp::CHUNK_XMP, RiffContent::Data(xmp(b"")))),
+        }
+        image.encoder().bytes()
+    }
+
+    fn contains(content: &[u8], needle: &[u8]) -> bool {
+        content.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn removes_the_location_from_the_exif_and_xmp_metadata() {
+        let allowed_types = ["jpg", "png", "webp"].map(str::to_string);
+        for (format, name) in [(ImageFormat::Jpeg, "photo.jpg"), (ImageFormat::Png, "photo.png"), (ImageFormat::WebP, "photo.webp")] {
+            let tagged = gps_tagged(format);
+            assert!(contains(&tagged, b"GPSLatitude"), "{name}");
+            let (_, stripped) = prepare(name, tagged, &allowed_types).unwrap();
+            assert!(!contains(&stripped, b"GPS"), "{name}");
+            assert!(!contains(&stripped, b"xmpmeta"), "{name}");
+            assert_eq!(image::load_from_memory(&stripped).unwrap().width(), 2, "{name}");
+        }
+    }
+}
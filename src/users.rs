@@ -0,0 +1,475 @@
+use std::fmt;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::totp;
+use tracing::error;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Accounts allowed to log in, kept in a JSON file next to the website rather than in the repository
+#[derive(Default, Deserialize, Serialize)]
+pub struct UserStore {
+    pub users: Vec<User>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct User {
+    pub username: String,
+    // Argon2id PHC string, eg $argon2id$v=19$m=19456,t=2,p=1$...
+    pub password_hash: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    // Disabled accounts are kept for the history but cannot log in
+    #[serde(default)]
+    pub disabled: bool,
+    // Second factor, enrolled with the user totp command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<TotpEnrolment>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TotpEnrolment {
+    // Base32 shared secret of the authenticator app
+    pub secret: String,
+    // Argon2id hashes of the recovery codes not used yet
+    pub recovery_codes: Vec<String>,
+}
+
+// Second factor accepted by UserStore::verify_second_factor
+pub enum SecondFactor {
+    Totp,
+    // Hash of the recovery code, to consume with consume_recovery_code
+    RecoveryCode(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Editor,
+    Member,
+}
+
+impl Role {
+    // Admins can do everything editors can, and editors everything members can
+    pub fn grants(self, required: Role) -> bool {
+        let rank = |role: Role| match role {
+            Role::Admin => 2,
+            Role::Editor => 1,
+            Role::Member => 0,
+        };
+        rank(self) >= rank(required)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "admin" => Some(Role::Admin),
+            "editor" => Some(Role::Editor),
+            "member" => Some(Role::Member),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::Admin => "admin",
+            Role::Editor => "editor",
+            Role::Member => "member",
+        };
+        f.write_str(name)
+    }
+}
+
+impl UserStore {
+    // Read the store, a missing file means that nobody can log in
+    pub fn load(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| format!("invalid user store {path}: {e}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(UserStore::default()),
+            Err(e) => Err(format!("cannot read user store {path}: {e}")),
+        }
+    }
+
+    // Re-read the store and write back the changes of the function, holding the lock of the file
+    // so that concurrent logins and commands do not overwrite each other's changes
+    pub fn update<T>(path: &str, change: impl FnOnce(&mut UserStore) -> Result<T, String>) -> Result<T, String> {
+        let _lock = lock_file(path)?;
+        let mut store = UserStore::load(path)?;
+        let result = change(&mut store)?;
+        store.save(path)?;
+        Ok(result)
+    }
+
+    // Write to a temporary file first, so that the server never reads a partial store
+    fn save(&self, path: &str) -> Result<(), String> {
+        if let Some(folder) = Path::new(path).parent().filter(|folder| !folder.as_os_str().is_empty()) {
+            fs::create_dir_all(folder).map_err(|e| format!("cannot create {}: {e}", folder.display()))?;
+        }
+        let content = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        write_private(Path::new(path), (content + "\n").as_bytes()).map_err(|e| format!("cannot write {path}: {e}"))
+    }
+
+    pub fn find(&self, username: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.username == username)
+    }
+
+    fn find_mut(&mut self, username: &str) -> Result<&mut User, String> {
+        self.users.iter_mut().find(|user| user.username == username).ok_or(format!("unknown user '{username}'"))
+    }
+
+    // The user matching the credentials, if any.
+    // Unknown usernames are checked against a dummy hash so that they take as long as wrong passwords
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<&User> {
+        match self.find(username) {
+            Some(user) if verify_password(password, &user.password_hash) && !user.disabled => Some(user),
+            Some(_) => None,
+            None => {
+                verify_password(password, dummy_hash());
+                None
+            }
+        }
+    }
+
+    // Check a TOTP code or a recovery code of an enrolled user. A TOTP code cannot be used twice,
+    // a recovery code must be consumed with consume_recovery_code
+    pub fn verify_second_factor(&self, username: &str, code: &str) -> Option<SecondFactor> {
+        let enrolment = self.find(username)?.totp.as_ref()?;
+        if totp::is_code(code) {
+            let now = Utc::now().timestamp().max(0) as u64;
+            let step = totp::verify(&enrolment.secret, code, now)?;
+            return accept_step(username, step).then_some(SecondFactor::Totp)
+        }
+        let code = totp::normalize_recovery_code(code);
+        enrolment.recovery_codes.iter().find(|hash| verify_password(&code, hash)).map(|hash| SecondFactor::RecoveryCode(hash.clone()))
+    }
+}
+
+// Remember the time step of the last accepted TOTP code of each user, in memory as the codes expire within minutes
+fn accept_step(username: &str, step: u64) -> bool {
+    static LAST_STEPS: OnceLock<Mutex<HashMap<String, u64>>> = OnceLock::new();
+    let mut last_steps = LAST_STEPS.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    let last_step = last_steps.entry(username.to_string()).or_default();
+    if step <= *last_step {
+        return false
+    }
+    *last_step = step;
+    true
+}
+
+// Remove a recovery code from the store, false when a concurrent login or command already removed it
+pub fn consume_recovery_code(path: &str, username: &str, hash: &str) -> Result<bool, String> {
+    UserStore::update(path, |store| {
+        let recovery_codes = match store.find_mut(username)?.totp.as_mut() {
+            Some(enrolment) => &mut enrolment.recovery_codes,
+            None => return Ok(false),
+        };
+        let before = recovery_codes.len();
+        recovery_codes.retain(|code| code != hash);
+        Ok(recovery_codes.len() < before)
+    })
+}
+
+// Exclusive lock of a file, held by processes and threads alike until the returned file is dropped
+pub(crate) fn lock_file(path: &str) -> Result<File, String> {
+    let lock_path = format!("{path}.lock");
+    if let Some(folder) = Path::new(&lock_path).parent().filter(|folder| !folder.as_os_str().is_empty()) {
+        fs::create_dir_all(folder).map_err(|e| format!("cannot create {}: {e}", folder.display()))?;
+    }
+    let file = private_options().write(true).create(true).truncate(false).open(&lock_path)
+        .map_err(|e| format!("cannot open {lock_path}: {e}"))?;
+    file.lock().map_err(|e| format!("cannot lock {lock_path}: {e}"))?;
+    Ok(file)
+}
+
+// Files created readable by their owner only. Other systems keep their default permissions,
+// eg the ACLs of the folder on Windows
+#[cfg(unix)]
+fn private_options() -> OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut options = OpenOptions::new();
+    options.mode(0o600);
+    options
+}
+
+#[cfg(not(unix))]
+fn private_options() -> OpenOptions {
+    OpenOptions::new()
+}
+
+// Replace a file readable by its owner only, through a temporary file of its own so that concurrent writers never share one
+pub(crate) fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+    let temporary = path.with_extension(format!("{}.{}.tmp", std::process::id(), TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)));
+    let written = private_options().write(true).create_new(true).open(&temporary)
+        .and_then(|mut file| file.write_all(content).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temporary, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written
+}
+
+// Create an account, members by default
+pub fn add_user(path: &str, username: &str, display_name: &str, roles: Vec<Role>, password: &str) -> Result<(), String> {
+    if username.is_empty() || username.chars().any(char::is_whitespace) {
+        return Err(format!("invalid username '{username}', it must not be empty nor contain spaces"))
+    }
+    let password_hash = hash_new_password(password)?;
+    let roles = match roles.is_empty() {
+        true => vec![Role::Member],
+        false => roles,
+    };
+    UserStore::update(path, |store| {
+        if store.find(username).is_some() {
+            return Err(format!("user '{username}' already exists"))
+        }
+        store.users.push(User {
+            username: username.to_string(),
+            password_hash,
+            display_name: display_name.to_string(),
+            roles,
+            disabled: false,
+            totp: None,
+        });
+        Ok(())
+    })
+}
+
+pub fn set_password(path: &str, username: &str, password: &str) -> Result<(), String> {
+    let password_hash = hash_new_password(password)?;
+    UserStore::update(path, |store| {
+        store.find_mut(username)?.password_hash = password_hash;
+        Ok(())
+    })
+}
+
+pub fn disable_user(path: &str, username: &str) -> Result<(), String> {
+    UserStore::update(path, |store| {
+        store.find_mut(username)?.disabled = true;
+        Ok(())
+    })
+}
+
+// Enrol a new authenticator for the user, replacing the previous one.
+// Returns the otpauth:// URI to scan and the recovery codes, which are only stored hashed
+pub fn enable_totp(path: &str, username: &str, issuer: &str) -> Result<(String, Vec<String>), String> {
+    let secret = totp::generate_secret();
+    let recovery_codes = totp::generate_recovery_codes();
+    let hashes = recovery_codes.iter()
+        .map(|code| hash_password(&totp::normalize_recovery_code(code)))
+        .collect::<Result<Vec<String>, String>>()?;
+    let uri = totp::otpauth_uri(issuer, username, &secret);
+    UserStore::update(path, |store| {
+        store.find_mut(username)?.totp = Some(TotpEnrolment { secret, recovery_codes: hashes });
+        Ok(())
+    })?;
+    Ok((uri, recovery_codes))
+}
+
+pub fn disable_totp(path: &str, username: &str) -> Result<(), String> {
+    UserStore::update(path, |store| {
+        store.find_mut(username)?.totp = None;
+        Ok(())
+    })
+}
+
+// One line per account: username, display name, roles, whether it has a second factor and whether it is disabled
+pub fn list_users(path: &str) -> Result<Vec<String>, String> {
+    let store = UserStore::load(path)?;
+    Ok(store.users.iter()
+        .map(|user| {
+            let roles = user.roles.iter().map(Role::to_string).collect::<Vec<String>>().join(",");
+            let totp = match user.totp {
+                Some(_) => "  (2FA)",
+                None => "",
+            };
+            let disabled = match user.disabled {
+                true => "  (disabled)",
+                false => "",
+            };
+            format!("{:<20} {:<30} {roles}{totp}{disabled}", user.username, user.display_name)
+        })
+        .collect())
+}
+
+fn hash_new_password(password: &str) -> Result<String, String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!("the password must have at least {MIN_PASSWORD_LENGTH} characters"))
+    }
+    hash_password(password)
+}
+
+// Argon2id hash with a random salt and the default parameters, as a PHC string
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("cannot hash password: {e}"))
+}
+
+// The parameters are read from the hash, so older hashes keep working if the defaults change
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(e) => {
+            error!("invalid password hash in the user store: {e}");
+            false
+        }
+    }
+}
+
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("not a password").unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::BASE32_NOPAD;
+
+    // Users file of a test in the temporary folder, removed with its lock at the end
+    struct UsersFile(String);
+
+    impl UsersFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("orailix-users-{}-{name}.json", std::process::id())).to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for UsersFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(format!("{}.lock", self.0));
+        }
+    }
+
+    fn user(username: &str) -> User {
+        User {
+            username: username.to_string(),
+            password_hash: String::new(),
+            display_name: String::new(),
+            roles: vec![Role::Member],
+            disabled: false,
+            totp: None,
+        }
+    }
+
+    fn generate_secret_bytes() -> Vec<u8> {
+        BASE32_NOPAD.decode(totp::generate_secret().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn verifies_the_passwords() {
+        let users = UsersFile::new("passwords");
+        assert!(add_user(&users.0, "alice", "Alice", Vec::new(), "short").is_err());
+        add_user(&users.0, "alice", "Alice", Vec::new(), "longpassword").unwrap();
+        let store = UserStore::load(&users.0).unwrap();
+        assert!(store.find("alice").unwrap().password_hash.starts_with("$argon2id$"));
+        assert_eq!(store.authenticate("alice", "longpassword").map(|user| user.roles.clone()), Some(vec![Role::Member]));
+        assert!(store.authenticate("alice", "longpassworD").is_none());
+        assert!(store.authenticate("bob", "longpassword").is_none());
+
+        disable_user(&users.0, "alice").unwrap();
+        assert!(UserStore::load(&users.0).unwrap().authenticate("alice", "longpassword").is_none());
+    }
+
+    #[test]
+    fn refuses_a_totp_code_used_twice() {
+        let users = UsersFile::new("totp");
+        let secret = generate_secret_bytes();
+        UserStore::update(&users.0, |store| {
+            let enrolment = TotpEnrolment { secret: BASE32_NOPAD.encode(&secret), recovery_codes: Vec::new() };
+            store.users.push(User { totp: Some(enrolment), ..user("totp-user") });
+            Ok(())
+        }).unwrap();
+        let store = UserStore::load(&users.0).unwrap();
+        let code = |step: u64| format!("{:06}", totp::hotp(&secret, step));
+        let step = Utc::now().timestamp() as u64 / 30;
+
+        // Outside of the window accepted for clocks slightly off
+        assert!(store.verify_second_factor("totp-user", &code(step - 3)).is_none());
+        assert!(store.verify_second_factor("totp-user", &code(step + 3)).is_none());
+        assert!(matches!(store.verify_second_factor("totp-user", &code(step)), Some(SecondFactor::Totp)));
+        assert!(store.verify_second_factor("totp-user", &code(step)).is_none());
+        // Nor can an older code of the window follow it
+        assert!(store.verify_second_factor("totp-user", &code(step - 1)).is_none());
+        assert!(matches!(store.verify_second_factor("totp-user", &code(step + 1)), Some(SecondFactor::Totp)));
+    }
+
+    #[test]
+    fn recovery_codes_are_single_use() {
+        let users = UsersFile::new("recovery");
+        let recovery_code = "abcd-efgh-ijkl";
+        let hash = hash_password(&totp::normalize_recovery_code(recovery_code)).unwrap();
+        UserStore::update(&users.0, |store| {
+            let enrolment = TotpEnrolment { secret: BASE32_NOPAD.encode(&generate_secret_bytes()), recovery_codes: vec![hash.clone()] };
+            store.users.push(User { totp: Some(enrolment), ..user("recovery-user") });
+            Ok(())
+        }).unwrap();
+
+        let store = UserStore::load(&users.0).unwrap();
+        let accepted = match store.verify_second_factor("recovery-user", "ABCD-EFGH-IJKL") {
+            Some(SecondFactor::RecoveryCode(accepted)) => accepted,
+            _ => panic!("the recovery code is refused"),
+        };
+        assert_eq!(accepted, hash);
+        assert_eq!(consume_recovery_code(&users.0, "recovery-user", &accepted), Ok(true));
+        // A concurrent login verified the same code before it was consumed
+        assert_eq!(consume_recovery_code(&users.0, "recovery-user", &accepted), Ok(false));
+        assert!(UserStore::load(&users.0).unwrap().verify_second_factor("recovery-user", recovery_code).is_none());
+    }
+
+    #[test]
+    fn concurrent_updates_are_all_written() {
+        let users = UsersFile::new("concurrent");
+        let threads: Vec<_> = (0..8).map(|index| {
+            let path = users.0.clone();
+            std::thread::spawn(move || UserStore::update(&path, |store| {
+                store.users.push(user(&format!("user-{index}")));
+                Ok(())
+            }))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(UserStore::load(&users.0).unwrap().users.len(), 8);
+
+        // Only the store and its lock are left in the folder
+        let prefix = format!("orailix-users-{}-concurrent.", std::process::id());
+        let mut files: Vec<String> = fs::read_dir(std::env::temp_dir()).unwrap()
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| name.starts_with(&prefix))
+            .collect();
+        files.sort();
+        assert_eq!(files, [format!("{prefix}json"), format!("{prefix}json.lock")]);
+    }
+
+    #[test]
+    fn private_files_are_replaced_as_a_whole() {
+        let users = UsersFile::new("private");
+        let path = Path::new(&users.0);
+        write_private(path, b"first version, longer than the second").unwrap();
+        write_private(path, b"second").unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"second");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::NaiveDate;
+use crate::articles::{parse_manifest, Manifest};
+
+pub const NEWS_PATH: &str = "orailix.com/news";
+
+// Problems found in an article folder: errors break the article in /api/articles, warnings do not
+#[derive(Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn print(&self, article_path: &Path) {
+        for error in &self.errors {
+            println!("error: {}: {error}", article_path.display());
+        }
+        for warning in &self.warnings {
+            println!("warning: {}: {warning}", article_path.display());
+        }
+    }
+}
+
+// Check an article folder the same way get_articles_handler reads it
+pub fn validate_article(article_path: &Path) -> Report {
+    let mut report = Report::default();
+
+    let has_manifest = article_path.join("manifest.txt").exists();
+    let has_markdown = article_path.join("index.md").exists();
+    let manifest = match parse_manifest(article_path) {
+        Some(manifest) => manifest,
+        None => {
+            report.errors.push(match (has_manifest, has_markdown) {
+                (false, false) => "no manifest.txt nor index.md".to_string(),
+                (true, _) => "manifest.txt has no title".to_string(),
+                (false, true) => "index.md has no front matter or no title".to_string(),
+            });
+            return report
+        }
+    };
+    if has_manifest && has_markdown {
+        report.warnings.push("both manifest.txt and index.md are present, the front matter of index.md is ignored".to_string());
+    }
+
+    let folder = article_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    match NaiveDate::parse_from_str(&manifest.date, "%Y-%d-%m") {
+        Ok(_) if !folder.starts_with(&manifest.date) => {
+            report.warnings.push(format!("folder name does not start with the article date {}", manifest.date));
+        }
+        Ok(_) => {}
+        Err(e) => report.errors.push(format!("date '{}' is not in the YYYY-DD-MM format: {e}", manifest.date)),
+    }
+
+    if manifest.picture.is_empty() {
+        report.warnings.push("no preview picture".to_string());
+    } else if let Some(picture) = local_file(&manifest, article_path, &manifest.picture) {
+        if !picture.exists() {
+            report.errors.push(format!("picture {} does not exist", picture.display()));
+        }
+    }
+
+    if manifest.page.is_empty() {
+        if !has_markdown && !article_path.join("index.html").exists() {
+            report.errors.push("no page: set one in the manifest or add an index.html or index.md".to_string());
+        }
+    } else if let Some(page) = local_file(&manifest, article_path, &manifest.page) {
+        if !page.exists() {
+            report.errors.push(format!("page {} does not exist", page.display()));
+        }
+    }
+    report
+}
+
+// Validate every article of every category, returns whether they are all valid
+pub fn validate_news() -> bool {
+    let mut valid = true;
+    let mut count = 0;
+    for category in sorted_entries(Path::new(NEWS_PATH)) {
+        for article in sorted_entries(&category) {
+            let report = validate_article(&article);
+            report.print(&article);
+            valid &= report.is_valid();
+            count += 1;
+        }
+    }
+    println!("{count} articles checked");
+    valid
+}
+
+// Local file a manifest entry points to, None for external URLs
+// Manifests with a page give paths relative to the news section, the others relative to the article folder
+fn local_file(manifest: &Manifest, article_path: &Path, location: &str) -> Option<PathBuf> {
+    if location.starts_with("http") {
+        None
+    } else if !manifest.page.is_empty() {
+        Some(Path::new(NEWS_PATH).join(location.trim_start_matches('/')))
+    } else {
+        Some(article_path.join(location))
+    }
+}
+
+fn sorted_entries(path: &Path) -> Vec<PathBuf> {
+    let mut entries = match fs::read_dir(path) {
+        // Hidden folders are the scratch copies of the admin API
+        Ok(entries) => entries.flatten()
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort();
+    entries
+}